@@ -0,0 +1,88 @@
+//! Throughput benchmarks for the CTMP parser and checksum.
+//!
+//! Run with `cargo bench`; results are reported in bytes per second so changes to
+//! buffering or the checksum loop show up as throughput regressions.
+
+use std::hint::black_box;
+use std::io::Cursor;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use wirestorm_core::{
+    compute_checksum, parse_ctmp_message, parse_ctmp_message_into, ParseConfig, HEADER_LEN, MAGIC,
+    SENSITIVE_BIT,
+};
+
+/// Payload sizes from a tiny message up to the 16-bit maximum
+const PAYLOAD_SIZES: [usize; 5] = [16, 256, 4096, 16384, 65535];
+
+/// Builds a frame with `len` payload bytes, with a valid checksum when sensitive.
+fn frame(len: usize, sensitive: bool) -> Vec<u8> {
+    let options = if sensitive { SENSITIVE_BIT } else { 0x00 };
+    let checksum: u16 = if sensitive { 0xCCCC } else { 0x0000 };
+    let mut bytes = vec![MAGIC, options];
+    bytes.extend_from_slice(&(len as u16).to_be_bytes());
+    bytes.extend_from_slice(&checksum.to_be_bytes());
+    bytes.extend_from_slice(&[0x00, 0x00]);
+    bytes.extend((0..len).map(|i| i as u8));
+
+    if sensitive {
+        let checksum = compute_checksum(&bytes);
+        bytes[4..6].copy_from_slice(&checksum.to_be_bytes());
+    }
+    bytes
+}
+
+fn bench_parse(c: &mut Criterion) {
+    for (name, sensitive) in [("parse_ctmp_message", false), ("parse_ctmp_message_sensitive", true)] {
+        let mut group = c.benchmark_group(name);
+        for len in PAYLOAD_SIZES {
+            let bytes = frame(len, sensitive);
+            group.throughput(Throughput::Bytes(bytes.len() as u64));
+            group.bench_with_input(BenchmarkId::from_parameter(len), &bytes, |b, bytes| {
+                b.iter(|| parse_ctmp_message(&mut Cursor::new(black_box(bytes))).unwrap())
+            });
+        }
+        group.finish();
+    }
+}
+
+/// Compares the proxy's per-frame work: parsing then re-encoding with `to_bytes`,
+/// against parsing into one buffer reused for every frame.
+fn bench_parse_into(c: &mut Criterion) {
+    let config = ParseConfig::default();
+    let mut group = c.benchmark_group("forward_frame");
+    for len in PAYLOAD_SIZES {
+        let bytes = frame(len, false);
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_with_input(BenchmarkId::new("to_bytes", len), &bytes, |b, bytes| {
+            b.iter(|| {
+                let message = parse_ctmp_message(&mut Cursor::new(black_box(bytes))).unwrap().unwrap();
+                message.to_bytes()
+            })
+        });
+
+        let mut buf = Vec::new();
+        group.bench_with_input(BenchmarkId::new("reused_buffer", len), &bytes, |b, bytes| {
+            b.iter(|| {
+                parse_ctmp_message_into(&mut Cursor::new(black_box(bytes)), &config, &mut buf).unwrap();
+                buf.len()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_checksum(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compute_checksum");
+    for len in PAYLOAD_SIZES {
+        let bytes = frame(len, false);
+        group.throughput(Throughput::Bytes((HEADER_LEN + len) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(len), &bytes, |b, bytes| {
+            b.iter(|| compute_checksum(black_box(bytes)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse, bench_parse_into, bench_checksum);
+criterion_main!(benches);
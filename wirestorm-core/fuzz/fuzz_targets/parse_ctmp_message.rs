@@ -0,0 +1,22 @@
+//! Feeds arbitrary bytes through `parse_ctmp_message` until the input is used up.
+//!
+//! The parser must never panic, and every call must either consume input or end the
+//! loop, so a `Cursor` over finite data always terminates. Any frame that parses must
+//! round-trip through `to_bytes`.
+
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use wirestorm_core::parse_ctmp_message;
+
+fuzz_target!(|data: &[u8]| {
+    let mut cursor = Cursor::new(data);
+
+    while let Ok(Some(message)) = parse_ctmp_message(&mut cursor) {
+        let bytes = message.to_bytes();
+        let reparsed = parse_ctmp_message(&mut Cursor::new(&bytes)).unwrap().unwrap();
+        assert_eq!(reparsed, message);
+    }
+});
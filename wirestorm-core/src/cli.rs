@@ -0,0 +1,193 @@
+//! Shared Command-Line Options
+//!
+//! Both proxies listen on a source and a destination port and apply the same read and
+//! write timeouts, so those options, and the helpers for parsing option values, live
+//! here. Each binary parses its own extra options and hands everything else to
+//! [`ListenConfig::apply`]. Every option has a default matching the original
+//! hardcoded values, so running a proxy with no arguments behaves as before.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+/// Read and write timeout used when none is given
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Listen address, ports and timeouts shared by both proxies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenConfig {
+    pub bind_addr: IpAddr,
+    pub source_port: u16,
+    pub dest_port: u16,
+    /// How long a source read may block; `None` waits forever
+    pub read_timeout: Option<Duration>,
+    /// How long a destination write may block; `None` waits forever
+    pub write_timeout: Option<Duration>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        ListenConfig {
+            bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            source_port: 33333,
+            dest_port: 44444,
+            read_timeout: Some(DEFAULT_TIMEOUT),
+            write_timeout: Some(DEFAULT_TIMEOUT),
+        }
+    }
+}
+
+impl ListenConfig {
+    /// Address the source listener binds to.
+    pub fn source_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_addr, self.source_port)
+    }
+
+    /// Address the destination listener binds to.
+    pub fn dest_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_addr, self.dest_port)
+    }
+
+    /// Applies `option` if it is one of the shared options, taking its value from
+    /// `args`. Returns `Ok(false)` for any other option, leaving `args` untouched.
+    pub fn apply<I: Iterator<Item = String>>(
+        &mut self,
+        option: &str,
+        args: &mut I,
+    ) -> Result<bool, ConfigError> {
+        match option {
+            "--bind-addr" => self.bind_addr = parse_value(option, args.next())?,
+            "--source-port" => self.source_port = parse_value(option, args.next())?,
+            "--dest-port" => self.dest_port = parse_value(option, args.next())?,
+            "--read-timeout" => self.read_timeout = parse_seconds(option, args.next())?,
+            "--write-timeout" => self.write_timeout = parse_seconds(option, args.next())?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Checks the options once every argument has been applied. Port 0 asks the OS
+    /// for a free port, so two listeners on port 0 never actually share one.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.source_port == self.dest_port && self.source_port != 0 {
+            return Err(ConfigError::SamePort(self.source_port));
+        }
+        Ok(())
+    }
+}
+
+/// Reasons the command line could not be turned into a configuration.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// An option was given without its value
+    MissingValue(String),
+    /// An option's value could not be parsed
+    InvalidValue { option: String, value: String },
+    /// An argument that isn't a known option
+    UnknownArgument(String),
+    /// Both listeners were given the same port
+    SamePort(u16),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingValue(option) => write!(f, "{} requires a value", option),
+            ConfigError::InvalidValue { option, value } => {
+                write!(f, "invalid value '{}' for {}", value, option)
+            }
+            ConfigError::UnknownArgument(arg) => write!(f, "unknown argument '{}'", arg),
+            ConfigError::SamePort(port) => write!(
+                f,
+                "source and destination ports must differ (both are {})",
+                port
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Parses the value following `option`.
+pub fn parse_value<T: std::str::FromStr>(
+    option: &str,
+    value: Option<String>,
+) -> Result<T, ConfigError> {
+    let value = value.ok_or_else(|| ConfigError::MissingValue(option.to_string()))?;
+    value.parse().map_err(|_| ConfigError::InvalidValue {
+        option: option.to_string(),
+        value,
+    })
+}
+
+/// Parses a duration in whole seconds, where `0` disables whatever it controls.
+pub fn parse_seconds(option: &str, value: Option<String>) -> Result<Option<Duration>, ConfigError> {
+    let secs: u64 = parse_value(option, value)?;
+    Ok((secs > 0).then(|| Duration::from_secs(secs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Applies every argument, as a proxy with no options of its own would.
+    fn parse(args: &[&str]) -> Result<ListenConfig, ConfigError> {
+        let mut config = ListenConfig::default();
+        let mut args = args.iter().map(|arg| arg.to_string());
+        while let Some(option) = args.next() {
+            if !config.apply(&option, &mut args)? {
+                return Err(ConfigError::UnknownArgument(option));
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn defaults_match_original_ports() {
+        let config = parse(&[]).unwrap();
+        assert_eq!(config, ListenConfig::default());
+        assert_eq!(config.source_addr().to_string(), "0.0.0.0:33333");
+        assert_eq!(config.dest_addr().to_string(), "0.0.0.0:44444");
+    }
+
+    #[test]
+    fn options_override_defaults() {
+        let args = ["--source-port", "4000", "--dest-port", "5000", "--bind-addr", "127.0.0.1"];
+        let config = parse(&args).unwrap();
+        assert_eq!(config.source_addr().to_string(), "127.0.0.1:4000");
+        assert_eq!(config.dest_addr().to_string(), "127.0.0.1:5000");
+    }
+
+    #[test]
+    fn timeouts_are_in_seconds_and_zero_disables() {
+        let config = parse(&[]).unwrap();
+        assert_eq!(config.read_timeout, Some(DEFAULT_TIMEOUT));
+        assert_eq!(config.write_timeout, Some(DEFAULT_TIMEOUT));
+
+        let config = parse(&["--read-timeout", "5", "--write-timeout", "0"]).unwrap();
+        assert_eq!(config.read_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(config.write_timeout, None);
+    }
+
+    #[test]
+    fn same_ports_are_rejected() {
+        assert_eq!(parse(&["--dest-port", "33333"]), Err(ConfigError::SamePort(33333)));
+        assert!(parse(&["--source-port", "0", "--dest-port", "0"]).is_ok());
+    }
+
+    #[test]
+    fn bad_values_are_rejected() {
+        assert_eq!(
+            parse(&["--source-port"]),
+            Err(ConfigError::MissingValue("--source-port".to_string()))
+        );
+        assert_eq!(
+            parse(&["--dest-port", "70000"]),
+            Err(ConfigError::InvalidValue {
+                option: "--dest-port".to_string(),
+                value: "70000".to_string(),
+            })
+        );
+    }
+}
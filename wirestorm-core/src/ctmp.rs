@@ -0,0 +1,872 @@
+//! CTMP Message Parser with Checksum Support
+//!
+//! This module parses CoreTech Message Protocol (CTMP) messages from any `Read` source,
+//! such as a `TcpStream`. Each message consists of an 8-byte header followed by a payload.
+//! If the message is marked as "sensitive" (bit 6 of the options byte), a 16-bit one's
+//! complement checksum is validated. The parser returns a `CtmpMessage` which can be
+//! turned back into its wire form for broadcasting, `None` on a clean end of stream,
+//! or a `CtmpError` describing why the stream could not be parsed.
+
+use std::fmt;
+use std::io::{self, Read}; // For reading from any byte source
+
+/// Magic byte that starts every CTMP message
+pub const MAGIC: u8 = 0xCC;
+
+/// Size of the CTMP header in bytes
+pub const HEADER_LEN: usize = 8;
+
+/// Default cap on the advertised payload length. CTMP lengths are 16-bit, so by
+/// default every frame is allowed; a lower cap rejects frames before allocating.
+pub const MAX_PAYLOAD: usize = u16::MAX as usize;
+
+/// Parser settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseConfig {
+    /// Largest payload length accepted. A length exactly equal to this is allowed.
+    pub max_payload: usize,
+    /// Magic bytes accepted at the start of a message, e.g. an old and a new magic
+    /// during a protocol migration
+    pub magics: Vec<u8>,
+    /// Require the checksum field of non-sensitive messages to be zero. Part 1 treats
+    /// bytes 4-5 as padding; Part 2 ignores them unless the message is sensitive.
+    pub zero_checksum_unless_sensitive: bool,
+    /// Validate the checksum of every message, not just sensitive ones. Only useful
+    /// when every sender fills in the checksum field regardless of the sensitive bit.
+    pub audit_checksums: bool,
+}
+
+impl Default for ParseConfig {
+    fn default() -> Self {
+        ParseConfig {
+            max_payload: MAX_PAYLOAD,
+            magics: vec![MAGIC],
+            zero_checksum_unless_sensitive: false,
+            audit_checksums: false,
+        }
+    }
+}
+
+/// Options bit marking a message as sensitive (checksum must be validated)
+pub const SENSITIVE_BIT: u8 = 0b0100_0000;
+
+/// A parsed CTMP message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CtmpMessage {
+    /// Magic byte the message arrived with
+    pub magic: u8,
+    /// Options / flags byte
+    pub options: u8,
+    /// Payload length from the header
+    pub length: u16,
+    /// Checksum field as received
+    pub checksum: u16,
+    /// Padding bytes as received
+    pub padding: [u8; 2],
+    /// Message payload
+    pub payload: Vec<u8>,
+}
+
+impl CtmpMessage {
+    /// Returns whether the sensitive bit is set in the options byte.
+    pub fn is_sensitive(&self) -> bool {
+        (self.options & SENSITIVE_BIT) != 0
+    }
+
+    /// Rebuilds the wire form of the message (header + payload), exactly as it was
+    /// received, padding included.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        bytes.push(self.magic);
+        bytes.push(self.options);
+        bytes.extend_from_slice(&self.length.to_be_bytes());
+        bytes.extend_from_slice(&self.checksum.to_be_bytes());
+        bytes.extend_from_slice(&self.padding);
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+/// Reasons a CTMP message could not be read.
+#[derive(Debug)]
+pub enum CtmpError {
+    /// The stream closed part-way through a message
+    UnexpectedEof,
+    /// The message did not start with the CTMP magic byte
+    BadMagic(u8),
+    /// An options, reserved or padding byte held a value the protocol doesn't allow
+    BadReserved,
+    /// The advertised payload length exceeded the configured cap
+    PayloadTooLarge { length: u16, max: usize },
+    /// A sensitive message's checksum didn't match its contents
+    ChecksumMismatch { expected: u16, actual: u16 },
+    /// Any other IO error from the underlying stream
+    Io(io::Error),
+}
+
+impl fmt::Display for CtmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CtmpError::UnexpectedEof => write!(f, "stream closed mid-message"),
+            CtmpError::BadMagic(byte) => write!(f, "bad magic byte 0x{:02X}", byte),
+            CtmpError::BadReserved => write!(f, "non-zero reserved bytes"),
+            CtmpError::PayloadTooLarge { length, max } => {
+                write!(f, "payload length {} exceeds maximum {}", length, max)
+            }
+            CtmpError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "invalid checksum (expected 0x{:04X}, got 0x{:04X})",
+                expected, actual
+            ),
+            CtmpError::Io(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+impl CtmpError {
+    /// Returns whether the whole invalid frame was read, leaving the stream at the
+    /// start of the next frame so the source can keep sending.
+    pub fn frame_consumed(&self) -> bool {
+        matches!(self, CtmpError::ChecksumMismatch { .. })
+    }
+}
+
+impl std::error::Error for CtmpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CtmpError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CtmpError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            CtmpError::UnexpectedEof
+        } else {
+            CtmpError::Io(e)
+        }
+    }
+}
+
+/// Reads a full header into `header`.
+///
+/// Returns `Ok(false)` if the stream closed cleanly before the first byte, and
+/// `CtmpError::UnexpectedEof` if it closed part-way through the header.
+fn read_header<R: Read>(stream: &mut R, header: &mut [u8; HEADER_LEN]) -> Result<bool, CtmpError> {
+    loop {
+        match stream.read(&mut header[..1]) {
+            Ok(0) => return Ok(false),
+            Ok(_) => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    stream.read_exact(&mut header[1..])?;
+    Ok(true)
+}
+
+/// Incremental 16-bit one's complement checksum.
+///
+/// - Sum 16-bit words in big-endian order
+/// - Data may be fed in chunks of any size; a word split across two chunks is
+///   carried over to the next `update`
+/// - If the total length is odd, pad last byte with 0
+/// - Fold sum into 16 bits and return one's complement
+#[derive(Debug, Default, Clone)]
+pub struct ChecksumState {
+    sum: u32,
+    pending: Option<u8>, // High byte of a word split across chunks
+}
+
+impl ChecksumState {
+    /// Creates an empty checksum state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds the next chunk of data into the checksum.
+    pub fn update(&mut self, mut buf: &[u8]) {
+        // Complete a word left over from the previous chunk
+        if let Some(high) = self.pending.take() {
+            match buf.split_first() {
+                Some((low, rest)) => {
+                    self.add_word(u16::from_be_bytes([high, *low]));
+                    buf = rest;
+                }
+                None => {
+                    self.pending = Some(high);
+                    return;
+                }
+            }
+        }
+
+        // Sum all 16-bit words
+        let mut chunks = buf.chunks_exact(2);
+        for chunk in &mut chunks {
+            self.add_word(u16::from_be_bytes([chunk[0], chunk[1]]));
+        }
+
+        // Keep any remaining single byte for the next chunk
+        if let [last] = chunks.remainder() {
+            self.pending = Some(*last);
+        }
+    }
+
+    /// Returns the checksum of all data fed so far.
+    pub fn finalize(mut self) -> u16 {
+        // Handle any remaining single byte (pad with 0)
+        if let Some(last) = self.pending.take() {
+            self.add_word(u16::from_be_bytes([last, 0x00]));
+        }
+
+        !(self.sum as u16) // Return one's complement
+    }
+
+    fn add_word(&mut self, word: u16) {
+        self.sum += word as u32;
+
+        // Fold carry bits back into 16 bits so the sum never overflows
+        if (self.sum >> 16) != 0 {
+            self.sum = (self.sum & 0xFFFF) + (self.sum >> 16);
+        }
+    }
+}
+
+/// Compute 16-bit one's complement checksum over the provided buffer in one go.
+pub fn compute_checksum(buf: &[u8]) -> u16 {
+    let mut state = ChecksumState::new();
+    state.update(buf);
+    state.finalize()
+}
+
+/// Parses a single CTMP message from the stream using the default [`ParseConfig`].
+///
+/// See [`parse_ctmp_message_with_config`].
+pub fn parse_ctmp_message<R: Read>(stream: &mut R) -> Result<Option<CtmpMessage>, CtmpError> {
+    parse_ctmp_message_with_config(stream, &ParseConfig::default())
+}
+
+/// Parses a single CTMP message from the stream.
+///
+/// Messages must start with one of `config.magics`; the magic is kept in the
+/// returned message so it is forwarded unchanged. Messages advertising a payload
+/// longer than `config.max_payload` are rejected before the payload buffer is
+/// allocated. A length exactly equal to the cap is accepted.
+///
+/// Returns:
+/// - `Ok(Some(CtmpMessage))` if a full, valid message was read
+/// - `Ok(None)` if the stream closed cleanly between messages
+/// - `Err(CtmpError)` if the message is invalid, truncated or an IO error occurs
+pub fn parse_ctmp_message_with_config<R: Read>(
+    stream: &mut R,
+    config: &ParseConfig,
+) -> Result<Option<CtmpMessage>, CtmpError> {
+    // Allocate buffer for 8-byte header
+    let mut header = [0u8; HEADER_LEN];
+
+    // Attempt to read exactly 8 bytes for header
+    if !read_header(stream, &mut header)? {
+        return Ok(None); // Clean disconnect
+    }
+    let length = validate_header(&header, config)?;
+
+    // Read payload of `length` bytes
+    let mut payload = vec![0u8; length as usize];
+    stream.read_exact(&mut payload)?; // Stream closed unexpectedly
+    verify_checksum(&header, &payload, config)?;
+
+    Ok(Some(CtmpMessage {
+        magic: header[0],
+        options: header[1],
+        length,
+        checksum: u16::from_be_bytes([header[4], header[5]]),
+        padding: [header[6], header[7]],
+        payload,
+    }))
+}
+
+/// Parses a single CTMP message into `frame`, a buffer reused across messages.
+///
+/// Applies the same validation as [`parse_ctmp_message_with_config`], but instead of
+/// allocating a `CtmpMessage` it leaves the message's wire form (header + payload) in
+/// `frame`, replacing its previous contents. The buffer keeps its capacity, so a
+/// connection that reuses one buffer stops allocating once it has seen its largest
+/// frame. `frame` is cleared first and its contents are unspecified after an error.
+///
+/// Returns:
+/// - `Ok(true)` if a full, valid message was read into `frame`
+/// - `Ok(false)` if the stream closed cleanly between messages
+/// - `Err(CtmpError)` if the message is invalid, truncated or an IO error occurs
+pub fn parse_ctmp_message_into<R: Read>(
+    stream: &mut R,
+    config: &ParseConfig,
+    frame: &mut Vec<u8>,
+) -> Result<bool, CtmpError> {
+    Ok(parse_ctmp_message_resync_into(stream, config, frame, 0)?.is_some())
+}
+
+/// Parses a single CTMP message into `frame`, resynchronizing after a framing error.
+///
+/// Like [`parse_ctmp_message_into`], except that when the next bytes are not a valid
+/// header (a bad magic byte, or anything that fails validation while scanning) the
+/// stream is scanned forward for the next accepted magic byte and parsing restarts
+/// from there. At most `max_skip` bytes are skipped; past that the header error is
+/// returned as usual, so a stream of garbage can't hold the parser forever. With
+/// `max_skip` of zero this behaves exactly like [`parse_ctmp_message_into`].
+///
+/// Returns:
+/// - `Ok(Some(skipped))` if a full, valid message was read into `frame` after
+///   skipping `skipped` bytes (zero when the stream was already in sync)
+/// - `Ok(None)` if the stream closed cleanly between messages
+/// - `Err(CtmpError)` if the message is invalid, truncated or an IO error occurs
+pub fn parse_ctmp_message_resync_into<R: Read>(
+    stream: &mut R,
+    config: &ParseConfig,
+    frame: &mut Vec<u8>,
+    max_skip: usize,
+) -> Result<Option<usize>, CtmpError> {
+    frame.clear();
+
+    let mut header = [0u8; HEADER_LEN];
+    if !read_header(stream, &mut header)? {
+        return Ok(None); // Clean disconnect
+    }
+    let (length, skipped) = sync_header(stream, &mut header, config, max_skip)?;
+    let length = length as usize;
+
+    // Read the payload straight after the header, without zero-filling it first
+    frame.reserve(HEADER_LEN + length);
+    frame.extend_from_slice(&header);
+    let read = stream.take(length as u64).read_to_end(frame)?;
+    if read < length {
+        return Err(CtmpError::UnexpectedEof); // Stream closed unexpectedly
+    }
+    verify_checksum(&header, &frame[HEADER_LEN..], config)?;
+
+    Ok(Some(skipped))
+}
+
+/// Validates `header`, sliding it forward through the stream until it holds a valid
+/// header or more than `max_skip` bytes would be skipped. Returns the payload length
+/// and the number of bytes skipped.
+fn sync_header<R: Read>(
+    stream: &mut R,
+    header: &mut [u8; HEADER_LEN],
+    config: &ParseConfig,
+    max_skip: usize,
+) -> Result<(u16, usize), CtmpError> {
+    let mut skipped = 0;
+    loop {
+        let error = match validate_header(header, config) {
+            Ok(length) => return Ok((length, skipped)),
+            // Garbage that happens to start with a magic byte is skipped too
+            Err(e) if skipped > 0 || matches!(e, CtmpError::BadMagic(_)) => e,
+            Err(e) => return Err(e),
+        };
+
+        // Drop everything before the next candidate magic byte, or the whole header
+        let shift = header[1..]
+            .iter()
+            .position(|byte| config.magics.contains(byte))
+            .map_or(HEADER_LEN, |pos| pos + 1);
+        if skipped + shift > max_skip {
+            return Err(error);
+        }
+        header.copy_within(shift.., 0);
+        stream.read_exact(&mut header[HEADER_LEN - shift..])?;
+        skipped += shift;
+    }
+}
+
+/// Validates a header against `config`, returning the payload length it advertises.
+fn validate_header(header: &[u8; HEADER_LEN], config: &ParseConfig) -> Result<u16, CtmpError> {
+    // Validate "magic" byte to confirm it's a CTMP message
+    let magic = header[0];
+    if !config.magics.contains(&magic) {
+        return Err(CtmpError::BadMagic(magic));
+    }
+
+    let options = header[1];                             // Options / flags byte
+    let length = u16::from_be_bytes([header[2], header[3]]); // Payload length
+    let checksum = u16::from_be_bytes([header[4], header[5]]); // Provided checksum
+
+    // Only the sensitive bit may be set in the options byte
+    if options & !SENSITIVE_BIT != 0x00 {
+        return Err(CtmpError::BadReserved);
+    }
+
+    // The checksum field may be reserved unless the message is sensitive
+    if config.zero_checksum_unless_sensitive && options & SENSITIVE_BIT == 0 && checksum != 0x0000 {
+        return Err(CtmpError::BadReserved);
+    }
+
+    // header[6..8] = padding, which must be zero
+    if header[6..8] != [0x00, 0x00] {
+        return Err(CtmpError::BadReserved);
+    }
+
+    // Reject oversized frames before committing the allocation
+    if length as usize > config.max_payload {
+        return Err(CtmpError::PayloadTooLarge {
+            length,
+            max: config.max_payload,
+        });
+    }
+
+    Ok(length)
+}
+
+/// Validates the checksum of a sensitive message; other messages pass unless
+/// `config.audit_checksums` is set.
+fn verify_checksum(
+    header: &[u8; HEADER_LEN],
+    payload: &[u8],
+    config: &ParseConfig,
+) -> Result<(), CtmpError> {
+    if header[1] & SENSITIVE_BIT == 0 && !config.audit_checksums {
+        return Ok(());
+    }
+
+    // Checksum header + payload with checksum bytes set to 0xCCCC,
+    // feeding the payload in place rather than copying it
+    let mut state = ChecksumState::new();
+    state.update(&header[..4]);
+    state.update(&[0xCC, 0xCC]);
+    state.update(&header[6..]);
+    state.update(payload);
+
+    let calc = state.finalize(); // Compute checksum
+    let checksum = u16::from_be_bytes([header[4], header[5]]);
+
+    if calc != checksum {
+        return Err(CtmpError::ChecksumMismatch {
+            expected: calc,
+            actual: checksum,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Builds a raw CTMP frame with the given options, checksum field and payload.
+    fn frame(options: u8, checksum: u16, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![MAGIC, options];
+        bytes.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        bytes.extend_from_slice(&[0x00, 0x00]);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn parses_frame_from_cursor() {
+        let bytes = frame(0x00, 0x0000, b"hello");
+        let message = parse_ctmp_message(&mut Cursor::new(bytes.clone())).unwrap().unwrap();
+
+        assert_eq!(message.options, 0x00);
+        assert_eq!(message.length, 5);
+        assert_eq!(message.payload, b"hello");
+        assert_eq!(message.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn parse_consumes_exactly_one_frame() {
+        let first = frame(0x00, 0x0000, b"hello");
+        let second = frame(0x00, 0x0000, b"next");
+        let mut stream = Cursor::new([first.clone(), second.clone()].concat());
+
+        let message = parse_ctmp_message(&mut stream).unwrap().unwrap();
+        assert_eq!(message.to_bytes(), first);
+        assert_eq!(stream.position(), (HEADER_LEN + 5) as u64);
+
+        // The trailing bytes are left for the next call
+        let message = parse_ctmp_message(&mut stream).unwrap().unwrap();
+        assert_eq!(message.to_bytes(), second);
+    }
+
+    #[test]
+    fn bad_magic_is_dropped() {
+        let mut bytes = frame(0x00, 0x0000, b"hello");
+        bytes[0] = 0xFF;
+
+        assert!(matches!(
+            parse_ctmp_message(&mut Cursor::new(bytes)),
+            Err(CtmpError::BadMagic(0xFF))
+        ));
+    }
+
+    /// Straightforward one-shot checksum to check `ChecksumState` against.
+    fn reference_checksum(buf: &[u8]) -> u16 {
+        let mut sum: u32 = 0;
+        for chunk in buf.chunks(2) {
+            let word = match chunk {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                [high] => u16::from_be_bytes([*high, 0x00]),
+                _ => unreachable!(),
+            };
+            sum += word as u32;
+        }
+        while (sum >> 16) != 0 {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    #[test]
+    fn incremental_checksum_matches_one_shot() {
+        // Odd length with high bytes so carries have to be folded
+        let data: Vec<u8> = (0..=250u8).map(|i| i.wrapping_mul(37) | 0x80).collect();
+        let expected = reference_checksum(&data);
+        assert_eq!(compute_checksum(&data), expected);
+
+        for chunk_size in 1..=9 {
+            let mut state = ChecksumState::new();
+            for chunk in data.chunks(chunk_size) {
+                state.update(chunk);
+            }
+            assert_eq!(state.finalize(), expected, "chunk size {}", chunk_size);
+        }
+
+        // Every two-way split, including ones that cut a word in half
+        for split in 0..=data.len() {
+            let mut state = ChecksumState::new();
+            state.update(&data[..split]);
+            state.update(&[]);
+            state.update(&data[split..]);
+            assert_eq!(state.finalize(), expected, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn known_sensitive_frame_is_accepted() {
+        // Same frame as the Part 2 `t_basic` test buffer
+        let bytes = frame(SENSITIVE_BIT, 0xE43D, &[b'a'; 50]);
+        let message = parse_ctmp_message(&mut Cursor::new(bytes.clone())).unwrap().unwrap();
+
+        assert!(message.is_sensitive());
+        assert_eq!(message.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn sensitive_frame_with_valid_checksum_is_accepted() {
+        let mut bytes = frame(SENSITIVE_BIT, 0xCCCC, b"odd");
+        let checksum = reference_checksum(&bytes);
+        bytes[4..6].copy_from_slice(&checksum.to_be_bytes());
+
+        let message = parse_ctmp_message(&mut Cursor::new(bytes)).unwrap().unwrap();
+        assert_eq!(message.checksum, checksum);
+    }
+
+    #[test]
+    fn payload_length_cap_is_inclusive() {
+        let bytes = frame(0x00, 0x0000, &[0xAA; 16]);
+        let config = |max_payload| ParseConfig {
+            max_payload,
+            ..ParseConfig::default()
+        };
+
+        let at_cap = parse_ctmp_message_with_config(&mut Cursor::new(bytes.clone()), &config(16));
+        assert_eq!(at_cap.unwrap().map(|m| m.length), Some(16));
+
+        let over_cap = parse_ctmp_message_with_config(&mut Cursor::new(bytes), &config(15));
+        assert!(matches!(
+            over_cap,
+            Err(CtmpError::PayloadTooLarge { length: 16, max: 15 })
+        ));
+    }
+
+    #[test]
+    fn checksum_bytes_are_not_reserved() {
+        let mut bytes = frame(SENSITIVE_BIT, 0xCCCC, b"layout");
+        let checksum = reference_checksum(&bytes);
+        assert_ne!(checksum, 0x0000);
+        bytes[4..6].copy_from_slice(&checksum.to_be_bytes());
+
+        let message = parse_ctmp_message(&mut Cursor::new(bytes)).unwrap().unwrap();
+        assert_eq!(message.checksum, checksum);
+    }
+
+    #[test]
+    fn checksum_bytes_can_be_reserved_when_not_sensitive() {
+        let bytes = frame(0x00, 0xE43D, &[b'a'; 50]);
+        let config = ParseConfig {
+            zero_checksum_unless_sensitive: true,
+            ..ParseConfig::default()
+        };
+
+        // Ignored by default, as in Part 2
+        let message = parse_ctmp_message(&mut Cursor::new(bytes.clone())).unwrap().unwrap();
+        assert_eq!(message.checksum, 0xE43D);
+
+        assert!(matches!(
+            parse_ctmp_message_with_config(&mut Cursor::new(bytes), &config),
+            Err(CtmpError::BadReserved)
+        ));
+    }
+
+    #[test]
+    fn audit_mode_validates_every_checksum() {
+        let config = ParseConfig {
+            audit_checksums: true,
+            ..ParseConfig::default()
+        };
+        let mut valid = frame(0x00, 0xCCCC, b"audited");
+        let checksum = reference_checksum(&valid);
+        valid[4..6].copy_from_slice(&checksum.to_be_bytes());
+        let invalid = frame(0x00, checksum ^ 0x0101, b"audited");
+
+        let message = parse_ctmp_message_with_config(&mut Cursor::new(valid.clone()), &config)
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.to_bytes(), valid);
+        assert!(matches!(
+            parse_ctmp_message_with_config(&mut Cursor::new(invalid.clone()), &config),
+            Err(CtmpError::ChecksumMismatch { .. })
+        ));
+
+        // Outside audit mode the same frame passes untouched
+        let message = parse_ctmp_message(&mut Cursor::new(invalid.clone())).unwrap().unwrap();
+        assert_eq!(message.to_bytes(), invalid);
+    }
+
+    #[test]
+    fn only_the_sensitive_option_bit_may_be_set() {
+        let bytes = frame(0x01, 0x0000, b"hello");
+        assert!(matches!(
+            parse_ctmp_message(&mut Cursor::new(bytes)),
+            Err(CtmpError::BadReserved)
+        ));
+    }
+
+    #[test]
+    fn padding_bytes_must_be_zero() {
+        let mut bytes = frame(SENSITIVE_BIT, 0xCCCC, b"layout");
+        let checksum = reference_checksum(&bytes);
+        bytes[4..6].copy_from_slice(&checksum.to_be_bytes());
+        bytes[7] = 0x01;
+
+        assert!(matches!(
+            parse_ctmp_message(&mut Cursor::new(bytes)),
+            Err(CtmpError::BadReserved)
+        ));
+    }
+
+    #[test]
+    fn to_bytes_writes_padding_as_received() {
+        let mut message = parse_ctmp_message(&mut Cursor::new(frame(0x00, 0x0000, b"pad")))
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.padding, [0x00, 0x00]);
+
+        // Nothing is rewritten on the way back out, so a message built with other
+        // padding re-encodes with it rather than silently zeroing it
+        message.padding = [0x12, 0x34];
+        assert_eq!(message.to_bytes()[6..8], [0x12, 0x34]);
+    }
+
+    #[test]
+    fn any_configured_magic_is_accepted_and_preserved() {
+        let config = ParseConfig {
+            magics: vec![MAGIC, 0xCD],
+            ..ParseConfig::default()
+        };
+
+        for magic in [MAGIC, 0xCD] {
+            let mut bytes = frame(0x00, 0x0000, b"migrate");
+            bytes[0] = magic;
+
+            let message = parse_ctmp_message_with_config(&mut Cursor::new(bytes.clone()), &config)
+                .unwrap()
+                .unwrap();
+            assert_eq!(message.magic, magic);
+            assert_eq!(message.to_bytes(), bytes);
+        }
+
+        let mut bytes = frame(0x00, 0x0000, b"migrate");
+        bytes[0] = 0xCE;
+        assert!(matches!(
+            parse_ctmp_message_with_config(&mut Cursor::new(bytes), &config),
+            Err(CtmpError::BadMagic(0xCE))
+        ));
+    }
+
+    #[test]
+    fn empty_stream_is_none() {
+        assert!(matches!(parse_ctmp_message(&mut Cursor::new(Vec::new())), Ok(None)));
+    }
+
+    #[test]
+    fn truncated_frame_is_unexpected_eof() {
+        let mut bytes = frame(0x00, 0x0000, b"hello");
+
+        bytes.truncate(HEADER_LEN + 2);
+        assert!(matches!(
+            parse_ctmp_message(&mut Cursor::new(bytes.clone())),
+            Err(CtmpError::UnexpectedEof)
+        ));
+
+        bytes.truncate(3);
+        assert!(matches!(
+            parse_ctmp_message(&mut Cursor::new(bytes)),
+            Err(CtmpError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn wrong_checksum_is_forwarded_on_non_sensitive_frames() {
+        // The checksum field isn't validated and is passed on as sent
+        let bytes = frame(0x00, 0xBEEF, b"hello");
+        let message = parse_ctmp_message(&mut Cursor::new(bytes.clone())).unwrap().unwrap();
+        assert_eq!(message.checksum, 0xBEEF);
+        assert_eq!(message.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn sensitive_frame_with_invalid_checksum_is_rejected() {
+        let bytes = frame(SENSITIVE_BIT, 0xBEEF, b"hello");
+        let err = parse_ctmp_message(&mut Cursor::new(bytes)).unwrap_err();
+        assert!(matches!(err, CtmpError::ChecksumMismatch { actual: 0xBEEF, .. }));
+        // The whole frame was read, so the next one can still be parsed
+        assert!(err.frame_consumed());
+    }
+
+    /// Reader that hands out as much as the caller asks for in one `read`, as a socket
+    /// does when TCP has coalesced several frames into one segment.
+    struct CoalescedReader {
+        data: Cursor<Vec<u8>>,
+        reads: usize,
+    }
+
+    impl Read for CoalescedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn frames_coalesced_into_one_read_both_parse() {
+        let first = frame(0x00, 0x0000, b"first");
+        let second = frame(0x00, 0x0000, b"second frame");
+        let reader = CoalescedReader {
+            data: Cursor::new([first.clone(), second.clone()].concat()),
+            reads: 0,
+        };
+        let mut stream = std::io::BufReader::new(reader);
+
+        let message = parse_ctmp_message(&mut stream).unwrap().unwrap();
+        assert_eq!(message.to_bytes(), first);
+        assert_eq!(stream.get_ref().reads, 1, "both frames came from a single read");
+
+        let message = parse_ctmp_message(&mut stream).unwrap().unwrap();
+        assert_eq!(message.to_bytes(), second);
+        assert_eq!(stream.get_ref().reads, 1);
+        assert!(parse_ctmp_message(&mut stream).unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_into_reuses_buffer_across_frames() {
+        let first = frame(0x00, 0x0000, &[0xAA; 32]);
+        let mut second = frame(SENSITIVE_BIT, 0xCCCC, b"odd");
+        let checksum = reference_checksum(&second);
+        second[4..6].copy_from_slice(&checksum.to_be_bytes());
+
+        let mut stream = Cursor::new([first.clone(), second.clone()].concat());
+        let config = ParseConfig::default();
+        let mut buf = Vec::new();
+
+        assert!(parse_ctmp_message_into(&mut stream, &config, &mut buf).unwrap());
+        assert_eq!(buf, first);
+        let capacity = buf.capacity();
+
+        // The smaller frame replaces the first without reallocating
+        assert!(parse_ctmp_message_into(&mut stream, &config, &mut buf).unwrap());
+        assert_eq!(buf, second);
+        assert_eq!(buf.capacity(), capacity);
+
+        assert!(!parse_ctmp_message_into(&mut stream, &config, &mut buf).unwrap());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn resync_skips_garbage_before_the_next_frame() {
+        let first = frame(0x00, 0x0000, b"hello");
+        let second = frame(0x00, 0x0000, b"ok");
+
+        // A glitch byte, and garbage containing a false magic, between two frames
+        let mut bytes = first.clone();
+        bytes.push(0x42);
+        bytes.extend_from_slice(&second);
+        bytes.extend_from_slice(&[0x01, MAGIC, 0xFF, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07]);
+        bytes.extend_from_slice(&first);
+        let mut stream = Cursor::new(bytes);
+        let config = ParseConfig::default();
+        let mut buf = Vec::new();
+        let mut next = |buf: &mut Vec<u8>| {
+            parse_ctmp_message_resync_into(&mut stream, &config, buf, 16).unwrap()
+        };
+
+        assert_eq!(next(&mut buf), Some(0));
+        assert_eq!(buf, first);
+        assert_eq!(next(&mut buf), Some(1));
+        assert_eq!(buf, second);
+        assert_eq!(next(&mut buf), Some(9));
+        assert_eq!(buf, first);
+        assert_eq!(next(&mut buf), None);
+    }
+
+    #[test]
+    fn resync_gives_up_after_max_skip() {
+        let mut bytes = vec![0xFF; 32];
+        bytes.extend_from_slice(&frame(0x00, 0x0000, b"hello"));
+        let config = ParseConfig::default();
+        let mut buf = Vec::new();
+
+        assert!(matches!(
+            parse_ctmp_message_resync_into(&mut Cursor::new(&bytes), &config, &mut buf, 31),
+            Err(CtmpError::BadMagic(0xFF))
+        ));
+        let mut stream = Cursor::new(&bytes);
+        let skipped = parse_ctmp_message_resync_into(&mut stream, &config, &mut buf, 32);
+        assert_eq!(skipped.unwrap(), Some(32));
+
+        // Without resync, and for an in-sync header that fails validation, nothing is skipped
+        assert!(matches!(
+            parse_ctmp_message_into(&mut Cursor::new(&bytes), &config, &mut buf),
+            Err(CtmpError::BadMagic(0xFF))
+        ));
+        let mut bytes = frame(0x00, 0x0000, b"hello");
+        bytes[7] = 0x01;
+        bytes.extend_from_slice(&frame(0x00, 0x0000, b"hello"));
+        assert!(matches!(
+            parse_ctmp_message_resync_into(&mut Cursor::new(bytes), &config, &mut buf, 64),
+            Err(CtmpError::BadReserved)
+        ));
+    }
+
+    #[test]
+    fn parse_into_rejects_truncated_and_invalid_frames() {
+        let config = ParseConfig::default();
+        let mut buf = Vec::new();
+
+        let mut bytes = frame(0x00, 0x0000, b"hello");
+        bytes.truncate(HEADER_LEN + 2);
+        assert!(matches!(
+            parse_ctmp_message_into(&mut Cursor::new(bytes), &config, &mut buf),
+            Err(CtmpError::UnexpectedEof)
+        ));
+
+        let bytes = frame(SENSITIVE_BIT, 0xBEEF, b"hello");
+        assert!(matches!(
+            parse_ctmp_message_into(&mut Cursor::new(bytes), &config, &mut buf),
+            Err(CtmpError::ChecksumMismatch { actual: 0xBEEF, .. })
+        ));
+    }
+}
@@ -0,0 +1,37 @@
+//! WireStorm Core
+//!
+//! Reusable CoreTech Message Protocol (CTMP) parsing, shared by both WireStorm proxies
+//! so they validate messages identically. The crate can also be used on its own to
+//! embed CTMP parsing in another service without running a proxy:
+//!
+//! ```
+//! use std::io::Cursor;
+//! use wirestorm_core::{parse_ctmp_message, CtmpMessage, MAGIC};
+//!
+//! // Magic, options, length, checksum and padding, then a 5-byte payload
+//! let mut frame = vec![MAGIC, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00];
+//! frame.extend_from_slice(b"hello");
+//!
+//! let message: CtmpMessage = parse_ctmp_message(&mut Cursor::new(&frame)).unwrap().unwrap();
+//! assert_eq!(message.payload, b"hello");
+//! assert_eq!(message.to_bytes(), frame);
+//! ```
+//!
+//! `parse_ctmp_message` reads from anything implementing `Read`, returning `Ok(None)`
+//! when the stream ends cleanly between messages. Use [`parse_ctmp_message_with_config`]
+//! to cap payload sizes, accept additional magic bytes or validate every checksum, and
+//! [`parse_ctmp_message_into`] to read frames into one reused buffer per connection.
+//! [`parse_ctmp_message_resync_into`] additionally skips forward to the next frame
+//! after a framing error, for sources on noisy links.
+//!
+//! The [`cli`] module holds the command-line options both proxies share.
+
+pub mod cli;
+mod ctmp;
+
+pub use ctmp::{
+    compute_checksum, parse_ctmp_message, parse_ctmp_message_into, parse_ctmp_message_resync_into,
+    parse_ctmp_message_with_config,
+    ChecksumState, CtmpError, CtmpMessage, ParseConfig, HEADER_LEN, MAGIC, MAX_PAYLOAD,
+    SENSITIVE_BIT,
+};
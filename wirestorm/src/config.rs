@@ -0,0 +1,71 @@
+//! Command-Line Configuration
+//!
+//! Part 1 takes only the listen address, ports and timeouts shared with Part 2, so
+//! its configuration is [`cli::ListenConfig`] as is.
+
+use wirestorm_core::cli;
+
+pub use wirestorm_core::cli::ConfigError;
+
+/// Settings for the proxy.
+pub type Config = cli::ListenConfig;
+
+/// Usage text printed alongside a configuration error
+pub const USAGE: &str = "\
+Usage: wirestorm [--bind-addr ADDR] [--source-port PORT] [--dest-port PORT]
+                 [--read-timeout SECS] [--write-timeout SECS]
+
+  --bind-addr ADDR     Address both listeners bind to (default 0.0.0.0)
+  --source-port PORT   Port source clients connect to (default 33333)
+  --dest-port PORT     Port destination clients connect to (default 44444)
+  --read-timeout SECS  Seconds a source may send nothing before it is dropped
+                       (default 30, 0 disables)
+  --write-timeout SECS Seconds a write to a destination may block before it is
+                       dropped (default 30, 0 disables)";
+
+/// Parses command-line arguments, excluding the program name.
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config, ConfigError> {
+    let mut config = Config::default();
+    let mut args = args.into_iter();
+
+    while let Some(option) = args.next() {
+        if !config.apply(&option, &mut args)? {
+            return Err(ConfigError::UnknownArgument(option));
+        }
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, ConfigError> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn shared_options_are_parsed_and_validated() {
+        let config = parse(&["--source-port", "4000", "--read-timeout", "0"]).unwrap();
+        assert_eq!(config.source_addr().to_string(), "0.0.0.0:4000");
+        assert_eq!(config.read_timeout, None);
+
+        assert_eq!(parse(&["--dest-port", "33333"]), Err(ConfigError::SamePort(33333)));
+    }
+
+    #[test]
+    fn unknown_arguments_are_rejected() {
+        assert_eq!(
+            parse(&["--verbose"]),
+            Err(ConfigError::UnknownArgument("--verbose".to_string()))
+        );
+
+        // Part 2 options aren't accepted by Part 1
+        assert_eq!(
+            parse(&["--max-sources", "4"]),
+            Err(ConfigError::UnknownArgument("--max-sources".to_string()))
+        );
+    }
+}
@@ -1,113 +1,254 @@
 //! WireStorm CTMP Proxy (Part 1)
 //!
 //! This Rust program implements a simple CoreTech Message Protocol (CTMP) proxy.
-//! It listens for a single source client on port 33333 and multiple destination
-//! clients on port 44444. Messages from the source are parsed and then
-//! broadcasted to all connected destination clients. Invalid messages or
-//! failed writes result in the corresponding client being disconnected.
+//! It listens for source clients on port 33333 and multiple destination clients
+//! on port 44444; both ports can be changed with `--source-port` and `--dest-port`.
+//! Messages from the source are parsed and then passed to a single broadcaster
+//! thread, which writes each complete message to all connected destination clients
+//! in turn, so messages never interleave on a destination. Invalid messages, failed
+//! writes and reads or writes that exceed their timeout result in the corresponding
+//! client being disconnected. Events are logged through the `log` crate at `info`
+//! and above by default; set `RUST_LOG=debug` to also trace each forwarded message.
 
 use std::{
-    net::{TcpListener, TcpStream}, // For TCP network communication
-    sync::{Arc, Mutex},            // For thread-safe shared state
+    net::{SocketAddr, TcpListener, TcpStream}, // For TCP network communication
+    sync::{mpsc, Arc, Mutex},      // For thread-safe shared state and channels
     thread,                        // For multithreading
     io::Write,                     // For writing bytes to TCP streams
 };
 
-mod ctmp; // Module handling CTMP message parsing
+use log::{debug, error, info, warn}; // Leveled logging, configured by RUST_LOG
+
+mod config; // Module parsing command-line options
+
+use wirestorm_core as ctmp; // Shared CTMP message parsing
+
+/// Binds a listener, exiting with a clear message instead of panicking if the
+/// address is unavailable (e.g. already in use).
+fn bind_listener(addr: SocketAddr, role: &str) -> TcpListener {
+    match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind {} listener on {}: {}", role, addr, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Writes each message to every destination client, in the order messages arrive.
+/// Clients that fail a write are removed from the list.
+fn broadcast(messages: mpsc::Receiver<Arc<[u8]>>, dest_clients: Arc<Mutex<Vec<Arc<TcpStream>>>>) {
+    for message in messages {
+        // Snapshot the destination list so the lock isn't held while writing
+        let clients: Vec<Arc<TcpStream>> = match dest_clients.lock() {
+            Ok(clients) => clients.clone(),
+            Err(_) => {
+                // Mutex poisoned, log and stop broadcasting
+                error!("Mutex poisoned while broadcasting");
+                return;
+            }
+        };
+
+        // Write to every client, collecting the ones that fail
+        let mut failed: Vec<Arc<TcpStream>> = Vec::new();
+        for client in clients {
+            if let Err(e) = (&*client).write_all(&message) {
+                // If write fails, remove the client and log the error
+                if let Ok(addr) = client.peer_addr() {
+                    warn!("Dropping client ({}): {}", addr, e);
+                } else {
+                    warn!("Dropping client (unknown addr): {}", e);
+                }
+                failed.push(client);
+            }
+        }
+
+        // Remove failed clients in a short follow-up critical section
+        if !failed.is_empty() {
+            if let Ok(mut clients) = dest_clients.lock() {
+                clients.retain(|c| !failed.iter().any(|f| Arc::ptr_eq(c, f)));
+            } else {
+                error!("Mutex poisoned while removing destination clients");
+                return;
+            }
+        }
+    }
+}
 
 fn main() {
+    // Log at info unless RUST_LOG says otherwise
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let config = match config::parse_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, config::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    // Bind both listeners up front so a bind failure stops the proxy before it accepts anything
+    let dest_listener = bind_listener(config.dest_addr(), "destination");
+    let listener = bind_listener(config.source_addr(), "source");
+
     // Shared list of connected destination clients, wrapped in Arc<Mutex<>> for safe concurrent access
-    let dest_clients: Arc<Mutex<Vec<TcpStream>>> = Arc::new(Mutex::new(Vec::new()));
+    // Streams are shared via Arc so they can be written to after the lock is released
+    let dest_clients: Arc<Mutex<Vec<Arc<TcpStream>>>> = Arc::new(Mutex::new(Vec::new()));
 
-    // Destination listener setup (port 44444)
+    // Destination listener setup (port 44444 by default)
     {
         // Clone Arc pointer for use inside the thread
         let dest_clients = Arc::clone(&dest_clients);
 
         // Spawn a thread to accept destination client connections
         thread::spawn(move || {
-            // Bind TCP listener to all interfaces on port 44444
-            let listener = TcpListener::bind("0.0.0.0:44444").expect("Failed to bind 44444");
-            println!("Listening for destination clients on 44444...");
+            let listener = dest_listener;
+            info!("Listening for destination clients on {}...", config.dest_port);
 
             // Accept incoming connections in a loop
             for stream in listener.incoming() {
-                if let Ok(stream) = stream {
-                    // Print client address if available
-                    if let Ok(addr) = stream.peer_addr() {
-                        println!("Destination client connected: {}", addr);
-                    } else {
-                        println!("Destination client connected (unknown addr)");
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        // A client that disconnects before it is accepted fails here
+                        warn!("Destination connection failed: {}", e);
+                        continue;
                     }
+                };
 
-                    // Lock the shared destination client list and add the new client
-                    if let Ok(mut clients) = dest_clients.lock() {
-                        clients.push(stream);
-                    } else {
-                        // If mutex is poisoned, log error
-                        eprintln!("Mutex poisoned while adding destination client");
-                    }
+                // Print client address if available
+                if let Ok(addr) = stream.peer_addr() {
+                    info!("Destination client connected: {}", addr);
+                } else {
+                    info!("Destination client connected (unknown addr)");
+                }
+
+                // A wedged client fails its write after the timeout instead of stalling
+                // the broadcast
+                if let Err(e) = stream.set_write_timeout(config.write_timeout) {
+                    warn!("Dropping client: {}", e);
+                    continue;
+                }
+
+                // Lock the shared destination client list and add the new client
+                if let Ok(mut clients) = dest_clients.lock() {
+                    clients.push(Arc::new(stream));
+                } else {
+                    // If mutex is poisoned, log error
+                    error!("Mutex poisoned while adding destination client");
                 }
             }
         });
     }
 
-    // Source listener setup (port 33333)
-    let listener = TcpListener::bind("0.0.0.0:33333").expect("Failed to bind 33333");
-    println!("Waiting for source clients on port 33333...");
+    // Single broadcaster thread that every source feeds, so frames from different
+    // sources are written one at a time and never interleave on a destination
+    let (broadcaster, messages) = mpsc::channel::<Arc<[u8]>>();
+    {
+        let dest_clients = Arc::clone(&dest_clients);
+        thread::spawn(move || broadcast(messages, dest_clients));
+    }
+
+    // Source listener setup (port 33333 by default)
+    info!("Waiting for source clients on port {}...", config.source_port);
 
     // Accept incoming source client connections
     for stream in listener.incoming() {
-        if let Ok(stream) = stream {
-            // Print the address of the connected source client
-            if let Ok(addr) = stream.peer_addr() {
-                println!("Source connected from {}", addr);
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Source connection failed: {}", e);
+                continue;
             }
+        };
 
-            // Clone Arc pointer to share the destination client list with the new thread
-            let dest_clients = Arc::clone(&dest_clients);
+        // Print the address of the connected source client
+        if let Ok(addr) = stream.peer_addr() {
+            info!("Source connected from {}", addr);
+        }
 
-            // Spawn a thread to handle communication with this source client
-            thread::spawn(move || {
-                let mut stream = stream;
-
-                loop {
-                    // Parse CTMP messages from the source client
-                    match ctmp::parse_ctmp_message(&mut stream) {
-                        Ok(Some(message)) => {
-                            // Successfully parsed a message; broadcast to all destination clients
-                            if let Ok(mut clients) = dest_clients.lock() {
-                                // Retain only clients that successfully receive the message
-                                clients.retain_mut(|client| {
-                                    if let Err(e) = client.write_all(&message) {
-                                        // If write fails, remove the client and log the error
-                                        if let Ok(addr) = client.peer_addr() {
-                                            println!("Dropping client ({}): {}", addr, e);
-                                        } else {
-                                            println!("Dropping client (unknown addr): {}", e);
-                                        }
-                                        return false; // Remove client from list
-                                    }
-                                    true // Keep client in list
-                                });
-                            } else {
-                                // Mutex poisoned, log and exit the thread
-                                eprintln!("Mutex poisoned while broadcasting");
-                                break;
-                            }
-                        }
-                        Ok(None) => {
-                            // End-of-stream detected; disconnect source
-                            break;
-                        }
-                        Err(e) => {
-                            // Error while reading or parsing; log and disconnect source
-                            println!("Error reading from source: {}", e);
+        // Each source thread gets its own handle to the broadcaster channel
+        let broadcaster = broadcaster.clone();
+
+        // Spawn a thread to handle communication with this source client
+        thread::spawn(move || {
+            let mut stream = stream;
+
+            // A source that stalls, even mid-frame, is dropped after the read timeout
+            if let Err(e) = stream.set_read_timeout(config.read_timeout) {
+                warn!("Error reading from source: {}", e);
+                return;
+            }
+
+            // Part 1 headers have no checksum field, so bytes 4-5 are padding
+            // unless the message is sensitive
+            let parse_config = ctmp::ParseConfig {
+                zero_checksum_unless_sensitive: true,
+                ..ctmp::ParseConfig::default()
+            };
+
+            // One read buffer reused for every frame from this source
+            let mut frame = Vec::new();
+
+            loop {
+                // Parse CTMP messages from the source client
+                match ctmp::parse_ctmp_message_into(&mut stream, &parse_config, &mut frame) {
+                    Ok(true) => {
+                        // Successfully parsed a message; hand it to the broadcaster
+                        debug!("Forwarding {}-byte message", frame.len());
+                        if broadcaster.send(Arc::from(&frame[..])).is_err() {
+                            warn!("Broadcaster stopped; disconnecting source");
                             break;
                         }
                     }
+                    Ok(false) => {
+                        // End-of-stream detected; disconnect source
+                        break;
+                    }
+                    Err(e) => {
+                        // Error while reading or parsing; log and disconnect source
+                        warn!("Error reading from source: {}", e);
+                        break;
+                    }
                 }
-            });
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn destinations_that_disconnect_at_once_are_pruned() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dest_clients: Arc<Mutex<Vec<Arc<TcpStream>>>> = Arc::new(Mutex::new(Vec::new()));
+
+        // Each client is gone before the proxy has written anything to it
+        for _ in 0..10 {
+            drop(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+            let (stream, _) = listener.accept().unwrap();
+            dest_clients.lock().unwrap().push(Arc::new(stream));
         }
+
+        let (broadcaster, messages) = mpsc::channel::<Arc<[u8]>>();
+        let broadcaster_thread = {
+            let dest_clients = Arc::clone(&dest_clients);
+            thread::spawn(move || broadcast(messages, dest_clients))
+        };
+
+        // The first write to a closed peer can still succeed; a later one fails
+        let message: Arc<[u8]> = Arc::from(&[0xCC, 0x00, 0x00, 0x01, 0, 0, 0, 0, b'!'][..]);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !dest_clients.lock().unwrap().is_empty() && Instant::now() < deadline {
+            broadcaster.send(Arc::clone(&message)).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(dest_clients.lock().unwrap().is_empty());
+
+        drop(broadcaster);
+        broadcaster_thread.join().unwrap();
     }
 }
@@ -1,14 +1,60 @@
 import socket
+import struct
 import time
 import unittest
 from threading import Thread
 
 import buffers
-from client import create_receiver, create_sender
+from client import RECV_PORT, SEND_PORT, create_receiver, create_sender
 
 # Header metadata
 MAGIC_BYTE: int = 0xCC
 HEADER_SIZE: int = 8
+# Options bit marking a message as sensitive.
+SENSITIVE_BIT: int = 0x40
+
+
+def ctmp_checksum(frame: bytes) -> int:
+    """Compute the CTMP checksum of a frame, treating its checksum field as 0xCCCC.
+
+    Args:
+        frame (bytes): The full CTMP frame - header and payload.
+
+    Returns:
+        int: The 16-bit one's complement checksum.
+    """
+    buf = frame[:4] + b"\xcc\xcc" + frame[6:]
+    if len(buf) % 2:
+        buf += b"\x00"
+
+    total = sum(
+        int.from_bytes(buf[i : i + 2], byteorder="big") for i in range(0, len(buf), 2)
+    )
+    while total >> 16:
+        total = (total & 0xFFFF) + (total >> 16)
+
+    return ~total & 0xFFFF
+
+
+def make_frame(payload: bytes, sensitive: bool = False) -> bytes:
+    """Build a CTMP frame, filling in a valid checksum when sensitive.
+
+    Args:
+        payload (bytes): The message payload.
+        sensitive (bool, optional): Whether to set the sensitive bit. Defaults to False.
+
+    Returns:
+        bytes: The encoded frame.
+    """
+    options = SENSITIVE_BIT if sensitive else 0x00
+    frame = bytes([MAGIC_BYTE, options]) + len(payload).to_bytes(2, byteorder="big")
+    frame += b"\x00" * 4 + payload
+
+    if sensitive:
+        checksum = ctmp_checksum(frame).to_bytes(2, byteorder="big")
+        frame = frame[:4] + checksum + frame[6:]
+
+    return frame
 
 
 class TestSolution(unittest.TestCase):
@@ -66,6 +112,29 @@ class TestSolution(unittest.TestCase):
         finally:
             receiver.close()
 
+    def recv_exact(self, receiver: socket.socket, size: int) -> bytes:
+        """Receive exactly `size` bytes from a receiver.
+
+        Args:
+            receiver (socket.socket): The connected receiver.
+            size (int): Number of bytes to read.
+
+        Raises:
+            RuntimeError: If the connection breaks before `size` bytes arrive.
+        """
+        data_received = []
+        bytes_received = 0
+
+        while bytes_received < size:
+            data = receiver.recv(min(size - bytes_received, self.buffer_size))
+            if not data:
+                raise RuntimeError("Broken connection.")
+
+            data_received.append(data)
+            bytes_received += len(data)
+
+        return b"".join(data_received)
+
     def setUp(self):
         """Set up the sender client."""
         self.data_received.clear()  # Nullify before each test.
@@ -132,6 +201,159 @@ class TestSolution(unittest.TestCase):
     def test_transmitted_with_sensitive_disabled(self):
         self._test_case(data=buffers.getb(buffers.t_basic_no_checksum))
 
+    def test_destination_instant_disconnect(self):
+        # Alternate graceful closes with RSTs so some sockets are dead before accept.
+        for i in range(200):
+            receiver = create_receiver()
+            if i % 2:
+                receiver.setsockopt(
+                    socket.SOL_SOCKET, socket.SO_LINGER, struct.pack("ii", 1, 0)
+                )
+            receiver.close()
+
+        self._test_case(data=buffers.getb(buffers.t_basic), thread_count=2)
+
+    def test_frame_not_over_read(self):
+        first = buffers.getb(buffers.t_small)
+        second = buffers.getb(buffers.t_basic)
+        split = HEADER_SIZE + 4
+        receiver: socket.socket = create_receiver()
+
+        try:
+            time.sleep(self.sleep_before_data_send_s)
+            # Follow the first frame with only the start of the second one.
+            self.sender.sendall(first + second[:split])
+            self.assertEqual(self.recv_exact(receiver, len(first)), first)
+
+            # The trailing bytes must be held back until their frame completes.
+            receiver.settimeout(self.sleep_before_data_send_s)
+            with self.assertRaises(socket.timeout):
+                receiver.recv(1)
+
+            receiver.settimeout(self.receiver_timeout_s)
+            self.sender.sendall(second[split:])
+            self.assertEqual(self.recv_exact(receiver, len(second)), second)
+        finally:
+            receiver.close()
+
+    def test_forwarded_frame_identical(self):
+        self._test_case(data=make_frame(b"\x00\x01fidelity\xff"))
+
+    def test_forwarded_sensitive_frame_identical(self):
+        self._test_case(data=make_frame(b"\x00\x01fidelity\xff", sensitive=True))
+
+    def test_checksum_preserved_end_to_end(self):
+        for data in (buffers.getb(buffers.t_basic), make_frame(b"odd", sensitive=True)):
+            with self.subTest(data=data[:HEADER_SIZE].hex()):
+                self._test_case(data=data)
+
+                # The destination must be able to re-validate the checksum itself.
+                received = self.data_received[0]
+                checksum = int.from_bytes(received[4:6], byteorder="big")
+                self.assertEqual(ctmp_checksum(received), checksum)
+
+    def test_rapid_source_reconnect(self):
+        source_count = 300
+        frames = [
+            make_frame(i.to_bytes(4, byteorder="big")) for i in range(source_count)
+        ]
+        receiver: socket.socket = create_receiver()
+
+        try:
+            time.sleep(self.sleep_before_data_send_s)
+            for frame in frames:
+                sender: socket.socket = create_sender()
+                sender.sendall(frame)
+                sender.close()
+
+            # Each source has its own thread, so frames may arrive in any order.
+            received = [self.recv_exact(receiver, len(frames[0])) for _ in frames]
+            self.assertCountEqual(received, frames)
+        finally:
+            receiver.close()
+
+    def test_failed_destination_does_not_skip_others(self):
+        data = buffers.getb(buffers.t_basic)
+        receivers: list[socket.socket] = []
+
+        try:
+            # Connect one at a time so the failing receiver sits mid-list.
+            for _ in range(3):
+                receivers.append(create_receiver())
+                time.sleep(0.1)
+
+            # Reset the middle receiver so writes to it fail.
+            receivers[1].setsockopt(
+                socket.SOL_SOCKET, socket.SO_LINGER, struct.pack("ii", 1, 0)
+            )
+            receivers[1].close()
+
+            time.sleep(self.sleep_before_data_send_s)
+            for _ in range(2):
+                self.sender.sendall(data)
+
+            for receiver in (receivers[0], receivers[2]):
+                self.assertEqual(self.recv_exact(receiver, 2 * len(data)), 2 * data)
+        finally:
+            for receiver in receivers:
+                receiver.close()
+
+    def test_checksum_ignored_when_not_sensitive(self):
+        frame = make_frame(b"not sensitive")
+        self._test_case(data=frame[:4] + b"\xbe\xef" + frame[6:])
+
+    def test_wrong_checksum_dropped_when_sensitive(self):
+        frame = make_frame(b"sensitive", sensitive=True)
+        self._test_case(data=frame[:4] + b"\xbe\xef" + frame[6:], expect_timeout=True)
+
+    def test_duplicate_source_still_forwarded(self):
+        # A second source from the same IP is only warned about, not refused.
+        first_sender = self.sender
+        self.sender = create_sender()
+
+        try:
+            self._test_case(data=buffers.getb(buffers.t_basic))
+        finally:
+            first_sender.close()
+
+    def test_coalesced_frames(self):
+        first = buffers.getb(buffers.t_small)
+        second = buffers.getb(buffers.t_basic)
+        receiver: socket.socket = create_receiver()
+
+        try:
+            time.sleep(self.sleep_before_data_send_s)
+            # Both frames go out in a single send, as if TCP coalesced them.
+            self.sender.sendall(first + second)
+            self.assertEqual(self.recv_exact(receiver, len(first)), first)
+            self.assertEqual(self.recv_exact(receiver, len(second)), second)
+        finally:
+            receiver.close()
+
+    def test_source_on_destination_port(self):
+        # A source connected to the wrong port is just a destination whose writes
+        # are ignored; it still receives broadcasts.
+        misdirected: socket.socket = create_sender(port=RECV_PORT)
+
+        try:
+            misdirected.sendall(buffers.getb(buffers.t_basic))
+            self._test_case(data=buffers.getb(buffers.t_basic), thread_count=2)
+        finally:
+            misdirected.close()
+
+    def test_destination_on_source_port(self):
+        # A destination connected to the wrong port sends nothing valid and is
+        # dropped without affecting the real source.
+        idle: socket.socket = create_receiver(port=SEND_PORT)
+        garbage: socket.socket = create_receiver(port=SEND_PORT)
+
+        try:
+            garbage.sendall(b"\x00" * HEADER_SIZE)
+            idle.close()
+            self._test_case(data=buffers.getb(buffers.t_basic), thread_count=2)
+        finally:
+            garbage.close()
+
     ##########################################################################
 
 
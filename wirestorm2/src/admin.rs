@@ -0,0 +1,274 @@
+//! Admin HTTP Endpoint
+//!
+//! A minimal, read-only HTTP responder for the admin port. `GET /metrics` returns the
+//! shared counters in the Prometheus text exposition format so the proxy can be
+//! scraped with standard tooling; every other request gets an error status. Each
+//! connection carries one request and is closed after the response.
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::metrics::Snapshot;
+
+/// Longest request head read before the request is rejected
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// How long a scraper may take to send its request or read the response
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One exported metric: its name, Prometheus type, help text and value.
+pub type Metric = (&'static str, &'static str, &'static str, u64);
+
+/// Lists every metric in `snapshot` except the per-error parse counts, which are
+/// exported as one labelled family. The StatsD pusher reports the same list.
+pub fn metric_list(snapshot: &Snapshot) -> Vec<Metric> {
+    vec![
+        (
+            "messages_forwarded_total",
+            "counter",
+            "Messages fanned out to destinations",
+            snapshot.messages_forwarded,
+        ),
+        (
+            "bytes_forwarded_total",
+            "counter",
+            "Bytes in forwarded messages, counted once per message",
+            snapshot.bytes_forwarded,
+        ),
+        (
+            "checksum_drops_total",
+            "counter",
+            "Frames dropped for a bad checksum",
+            snapshot.checksum_drops,
+        ),
+        (
+            "length_drops_total",
+            "counter",
+            "Frames dropped for a payload length other than the required one",
+            snapshot.length_drops,
+        ),
+        (
+            "denied_frames_total",
+            "counter",
+            "Frames dropped for matching a deny pattern",
+            snapshot.denied_frames,
+        ),
+        (
+            "anomaly_drops_total",
+            "counter",
+            "Frames dropped for a payload far longer than any forwarded before",
+            snapshot.anomaly_drops,
+        ),
+        (
+            "max_payload_bytes",
+            "gauge",
+            "Longest payload forwarded so far",
+            snapshot.max_payload,
+        ),
+        (
+            "deadline_skips_total",
+            "counter",
+            "Frames skipped for a destination after waiting past the frame deadline",
+            snapshot.deadline_skips,
+        ),
+        (
+            "audit_drops_total",
+            "counter",
+            "Frames dropped because the audit sink was unavailable",
+            snapshot.audit_drops,
+        ),
+        (
+            "resyncs_total",
+            "counter",
+            "Source streams resynchronized after a framing error",
+            snapshot.resyncs,
+        ),
+        (
+            "clean_disconnects_total",
+            "counter",
+            "Source and destination connections that ended cleanly",
+            snapshot.clean_disconnects,
+        ),
+        (
+            "error_disconnects_total",
+            "counter",
+            "Source and destination connections dropped on an error",
+            snapshot.error_disconnects,
+        ),
+        (
+            "sources_connected_total",
+            "counter",
+            "Source connections accepted since startup",
+            snapshot.sources_connected,
+        ),
+        (
+            "destinations_connected_total",
+            "counter",
+            "Destination connections accepted since startup",
+            snapshot.destinations_connected,
+        ),
+        (
+            "active_sources",
+            "gauge",
+            "Sources connected right now",
+            snapshot.active_sources,
+        ),
+        (
+            "active_destinations",
+            "gauge",
+            "Destinations connected right now",
+            snapshot.active_destinations,
+        ),
+    ]
+}
+
+/// Renders `snapshot` in the Prometheus text exposition format.
+pub fn render_metrics(snapshot: &Snapshot) -> String {
+    let metrics = metric_list(snapshot);
+
+    let mut body = String::new();
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(body, "# HELP {} {}", name, help);
+        let _ = writeln!(body, "# TYPE {} {}", name, kind);
+        let _ = writeln!(body, "{} {}", name, value);
+    }
+
+    let _ = writeln!(body, "# HELP parse_errors_total Frames rejected by the parser, by error");
+    let _ = writeln!(body, "# TYPE parse_errors_total counter");
+    for (kind, value) in snapshot.parse_errors.by_kind() {
+        let _ = writeln!(body, "parse_errors_total{{kind=\"{}\"}} {}", kind, value);
+    }
+    body
+}
+
+/// Answers a single request on `stream`, taking a fresh snapshot from `snapshot`
+/// only for `GET /metrics`.
+pub fn handle_request(
+    mut stream: TcpStream,
+    snapshot: impl FnOnce() -> Snapshot,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let response = match read_request_line(&mut stream)? {
+        Some(line) => {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some("GET"), Some("/metrics")) => {
+                    response("200 OK", "text/plain; version=0.0.4", &render_metrics(&snapshot()))
+                }
+                (Some("GET"), Some(_)) => response("404 Not Found", "text/plain", "not found\n"),
+                (Some(_), Some(_)) => {
+                    response("405 Method Not Allowed", "text/plain", "read-only endpoint\n")
+                }
+                _ => response("400 Bad Request", "text/plain", "bad request\n"),
+            }
+        }
+        None => response("400 Bad Request", "text/plain", "bad request\n"),
+    };
+
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+/// Reads the request head and returns its first line, or `None` if the head is
+/// malformed, too long or cut short.
+fn read_request_line(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            return Ok(None);
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    Ok(head.lines().next().map(str::to_string))
+}
+
+/// Builds a complete HTTP/1.1 response that closes the connection.
+fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::ParseErrors;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Sends `request` to a fresh handler and returns the raw response.
+    fn exchange(request: &str, snapshot: Snapshot) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let handler = thread::spawn(move || handle_request(stream, || snapshot));
+
+        client.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        handler.join().unwrap().unwrap();
+        response
+    }
+
+    #[test]
+    fn metrics_are_rendered_in_prometheus_format() {
+        let snapshot = Snapshot {
+            messages_forwarded: 3,
+            bytes_forwarded: 42,
+            active_destinations: 2,
+            parse_errors: ParseErrors {
+                bad_magic: 4,
+                ..ParseErrors::default()
+            },
+            ..Snapshot::default()
+        };
+        let body = render_metrics(&snapshot);
+
+        assert!(body.contains("# TYPE messages_forwarded_total counter\n"));
+        assert!(body.contains("messages_forwarded_total 3\n"));
+        assert!(body.contains("bytes_forwarded_total 42\n"));
+        assert!(body.contains("checksum_drops_total 0\n"));
+        assert!(body.contains("length_drops_total 0\n"));
+        assert!(body.contains("# TYPE active_destinations gauge\nactive_destinations 2\n"));
+        assert!(body.contains("active_sources 0\n"));
+        assert!(body.contains("parse_errors_total{kind=\"bad_magic\"} 4\n"));
+        assert!(body.contains("parse_errors_total{kind=\"io\"} 0\n"));
+    }
+
+    #[test]
+    fn get_metrics_is_served_and_everything_else_refused() {
+        let snapshot = Snapshot {
+            checksum_drops: 7,
+            ..Snapshot::default()
+        };
+
+        let response = exchange("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n", snapshot);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.ends_with(&render_metrics(&snapshot)));
+
+        let response = exchange("GET / HTTP/1.1\r\n\r\n", snapshot);
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let response = exchange("POST /metrics HTTP/1.1\r\n\r\n", snapshot);
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        let response = exchange("garbage\r\n\r\n", snapshot);
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
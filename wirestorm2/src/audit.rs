@@ -0,0 +1,132 @@
+//! Audit Sink
+//!
+//! Compliance deployments need a copy of every frame the proxy forwards. With an audit
+//! sink configured, the broadcaster writes each frame to the sink before fanning it
+//! out, so no destination sees a frame the sink wasn't sent. If the sink can't be
+//! reached, the sink's mode decides: fail-closed drops the frame, so nothing goes
+//! unaudited, while fail-open forwards it anyway, favouring availability. The sink is
+//! a plain TCP connection receiving the frames back to back, as a destination would.
+
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use crate::log_limit;
+
+/// How long to wait before reconnecting after the sink could not be reached, so an
+/// unreachable sink doesn't cost a connection attempt per frame
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a connection attempt to the sink may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Connection to the audit sink, reopened as needed.
+pub struct AuditSink {
+    addr: SocketAddr,
+    fail_open: bool,
+    write_timeout: Option<Duration>,
+    stream: Option<TcpStream>,
+    retry_at: Instant, // Earliest time the next connection attempt may be made
+}
+
+impl AuditSink {
+    /// Creates a sink sending to `addr`, connecting on the first frame. Writes block
+    /// for at most `write_timeout` before the sink counts as unreachable.
+    pub fn new(addr: SocketAddr, fail_open: bool, write_timeout: Option<Duration>) -> Self {
+        AuditSink {
+            addr,
+            fail_open,
+            write_timeout,
+            stream: None,
+            retry_at: Instant::now(),
+        }
+    }
+
+    /// Sends `frame` to the sink and returns whether it may be broadcast: always once
+    /// the sink has it, otherwise only in fail-open mode.
+    pub fn deliver(&mut self, frame: &[u8]) -> bool {
+        match self.write(frame) {
+            Ok(()) => true,
+            Err(e) => {
+                let action = if self.fail_open { "forwarding" } else { "dropping" };
+                log_limit::warn("audit sink", format_args!(
+                    "Audit sink {} unavailable ({}); {} frame",
+                    self.addr, e, action
+                ));
+                self.fail_open
+            }
+        }
+    }
+
+    /// Writes `frame`, connecting first if there's no connection. A failed write
+    /// closes the connection, so the next frame reconnects.
+    fn write(&mut self, frame: &[u8]) -> io::Result<()> {
+        let mut stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => self.connect()?,
+        };
+        if let Err(e) = stream.write_all(frame) {
+            self.retry_at = Instant::now() + RETRY_INTERVAL;
+            return Err(e);
+        }
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    fn connect(&mut self) -> io::Result<TcpStream> {
+        if Instant::now() < self.retry_at {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "waiting to reconnect"));
+        }
+        let stream = TcpStream::connect_timeout(&self.addr, CONNECT_TIMEOUT)
+            .and_then(|stream| {
+                stream.set_write_timeout(self.write_timeout)?;
+                stream.set_nodelay(true)?;
+                Ok(stream)
+            })
+            .inspect_err(|_| self.retry_at = Instant::now() + RETRY_INTERVAL)?;
+        log::info!("Connected to audit sink {}", self.addr);
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    /// Address nothing is listening on.
+    fn unreachable_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[test]
+    fn sink_receives_every_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sink = AuditSink::new(listener.local_addr().unwrap(), false, None);
+
+        let frames: Vec<Vec<u8>> = (0..50u8).map(|i| vec![i; 1 + usize::from(i)]).collect();
+        for frame in &frames {
+            assert!(sink.deliver(frame));
+        }
+        drop(sink);
+
+        let mut received = Vec::new();
+        listener.accept().unwrap().0.read_to_end(&mut received).unwrap();
+        assert_eq!(received, frames.concat());
+    }
+
+    #[test]
+    fn unreachable_sink_blocks_frames_unless_fail_open() {
+        let addr = unreachable_addr();
+
+        let mut closed = AuditSink::new(addr, false, None);
+        assert!(!closed.deliver(b"frame"));
+        // Retries are throttled, and frames stay blocked meanwhile
+        assert!(!closed.deliver(b"frame"));
+
+        let mut open = AuditSink::new(addr, true, None);
+        assert!(open.deliver(b"frame"));
+        assert!(open.deliver(b"frame"));
+    }
+}
@@ -0,0 +1,631 @@
+//! Command-Line Configuration
+//!
+//! Parses the traffic limits and admin options specific to Part 2 from the command
+//! line, on top of the listen address, ports and timeouts shared with Part 1 (see
+//! [`cli::ListenConfig`]). Every option has a default matching the original
+//! hardcoded values, so running the proxy with no arguments behaves as before.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use wirestorm_core::cli::{self, parse_seconds, parse_value};
+use wirestorm_core::{ParseConfig, MAGIC, MAX_PAYLOAD};
+
+use crate::deny::DenyPattern;
+use crate::log_limit;
+
+pub use wirestorm_core::cli::ConfigError;
+
+/// Frames queued per destination when no capacity is given
+pub const DEFAULT_DEST_QUEUE_CAPACITY: usize = 1024;
+
+/// Unexpected bytes a destination may send when no limit is given. Destinations are
+/// receive-only, so anything they send is only read to detect EOF.
+pub const DEFAULT_MAX_DEST_INBOUND: u64 = 64 * 1024;
+
+/// Interval between metrics summaries when none is given
+pub const DEFAULT_METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Interval between StatsD pushes when none is given
+pub const DEFAULT_STATSD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Usage text printed alongside a configuration error
+pub const USAGE: &str = "\
+Usage: wirestorm2 [--bind-addr ADDR] [--source-port PORT] [--dest-port PORT]
+                  [--read-timeout SECS] [--write-timeout SECS]
+                  [--dest-queue-capacity N] [--max-destinations N]
+                  [--max-sources N] [--max-conns-per-ip N]
+                  [--max-dest-inbound BYTES]
+                  [--dest-handshake] [--deterministic-order]
+                  [--frame-deadline-ms MS]
+                  [--max-consecutive-invalid N] [--max-throughput BYTES]
+                  [--resync-limit BYTES] [--max-payload BYTES]
+                  [--magic BYTE]... [--audit-checksums]
+                  [--exact-payload-len BYTES] [--deny-pattern PATTERN]...
+                  [--payload-anomaly-factor N] [--min-frame-gap-ms MS]
+                  [--header-timeout-ms MS]
+                  [--max-conn-lifetime SECS] [--source-rcvbuf BYTES]
+                  [--source-queue-depth N]
+                  [--audit-sink ADDR] [--audit-fail-open]
+                  [--metrics-interval SECS] [--log-summary-interval SECS]
+                  [--statsd ADDR] [--statsd-interval SECS]
+                  [--admin-port PORT] [--control-port PORT] [--admin-addr ADDR]
+                  [--event-log PATH] [--log-interval SECS]
+                  [--startup-hook CMD] [--shutdown-hook CMD]
+
+  --bind-addr ADDR     Address both listeners bind to (default 0.0.0.0)
+  --source-port PORT   Port source clients connect to (default 33333)
+  --dest-port PORT     Port destination clients connect to (default 44444)
+  --read-timeout SECS  Seconds a source may send nothing before it is dropped
+                       (default 30, 0 disables)
+  --write-timeout SECS Seconds a write to a destination may block before it is
+                       dropped (default 30, 0 disables)
+  --dest-queue-capacity N
+                       Frames queued for a destination before it is dropped
+                       as too slow (default 1024)
+  --max-destinations N Destinations connected at once; further connections
+                       are closed immediately (default 0, unlimited)
+  --max-sources N      Sources connected at once; further connections are
+                       closed immediately (default 0, unlimited)
+  --max-conns-per-ip N Sources and destinations connected at once from one IP
+                       address; further connections from it are closed
+                       immediately (default 0, unlimited)
+  --max-dest-inbound BYTES
+                       Bytes a destination may send the proxy before it is
+                       disconnected; destinations are receive-only
+                       (default 65536)
+  --dest-handshake     Hold each new destination back until it sends a byte
+                       saying it is ready (default: broadcast to it at once)
+  --deterministic-order
+                       Fan frames out to destinations in ascending connection
+                       order, for reproducible tests (default: registration
+                       order)
+  --frame-deadline-ms MS
+                       Milliseconds a frame may wait in a destination's queue;
+                       later frames are skipped for that destination, which
+                       stays connected (default 0, no deadline)
+  --max-consecutive-invalid N
+                       Bad-checksum frames in a row a source may send before it
+                       is disconnected (default 0)
+  --max-throughput BYTES
+                       Bytes per second forwarded across all destinations;
+                       bursts are paced out rather than dropped (default 0,
+                       unlimited)
+  --resync-limit BYTES Bytes a source may have skipped while scanning for the
+                       next frame after a framing error, instead of being
+                       disconnected (default 0, disconnect at once)
+  --max-payload BYTES  Largest payload length a source may advertise; longer
+                       frames disconnect the source before their payload is
+                       read (default 65535)
+  --magic BYTE         Magic byte accepted at the start of a frame, in decimal
+                       or 0x hex; repeat to accept several, e.g. during a
+                       protocol migration (default 0xCC)
+  --audit-checksums    Validate the checksum of every frame, not just sensitive
+                       ones; only for senders that always fill it in
+  --exact-payload-len BYTES
+                       Drop, and count, every frame whose payload length is
+                       not exactly BYTES (default: any length)
+  --deny-pattern PATTERN
+                       Drop, and count, every frame whose payload matches
+                       PATTERN: 0x-prefixed hex bytes found anywhere in it, or
+                       a glob (* and ?) matching all of it; repeat to deny
+                       several (default: no filtering)
+  --payload-anomaly-factor N
+                       Drop, and count, every frame whose payload is more than
+                       N times the longest forwarded so far (default 0, no
+                       check)
+  --min-frame-gap-ms MS
+                       Milliseconds each source's frames are spaced apart at
+                       least, holding back any sent closer together (default 0,
+                       no spacing)
+  --header-timeout-ms MS
+                       Milliseconds a frame's header may take to arrive once its
+                       first byte has; a source sending it slower is
+                       disconnected (default 0, no limit)
+  --max-conn-lifetime SECS
+                       Seconds a source or destination may stay connected;
+                       sources are closed after their current frame and
+                       destinations once their queue is sent (default 0,
+                       unlimited)
+  --source-rcvbuf BYTES
+                       Kernel receive buffer for source sockets, to absorb
+                       bursts (default 0, the system default)
+  --source-queue-depth N
+                       Frames each source may queue ahead of the broadcaster,
+                       so it keeps reading through brief fan-out stalls
+                       (default 0, hand frames straight to the broadcaster)
+  --audit-sink ADDR    Send every frame to ADDR (host:port) before any
+                       destination gets it (default: no audit sink)
+  --audit-fail-open    Forward frames while the audit sink is unreachable
+                       (default: drop them until it is back)
+  --metrics-interval SECS
+                       Seconds between logged metrics summaries (default 60,
+                       0 disables)
+  --log-summary-interval SECS
+                       Log connects and disconnects as one summary line every
+                       SECS instead of a line each (default 0, a line each)
+  --statsd ADDR        Push metrics to the StatsD server at ADDR (host:port)
+                       over UDP (default: no StatsD push)
+  --statsd-interval SECS
+                       Seconds between StatsD pushes (default 10)
+  --admin-port PORT    Serve Prometheus metrics at GET /metrics on this port
+                       (default: no admin listener)
+  --control-port PORT  Accept plain-text commands (stats, list-dest, list-src,
+                       drain) on this port (default: no control listener)
+  --admin-addr ADDR    Address the admin and control listeners bind to
+                       (default 127.0.0.1)
+  --event-log PATH     Append connection lifecycle events to PATH as JSON
+                       lines (default: no event log)
+  --log-interval SECS  Seconds between warnings of the same kind; repeats in
+                       between are counted instead (default 1, 0 logs every
+                       warning)
+  --startup-hook CMD   Shell command run once the listeners are bound, before
+                       any connection is accepted; the proxy exits if it fails
+                       (default: none)
+  --shutdown-hook CMD  Shell command run after a graceful shutdown (default:
+                       none)";
+
+/// Settings for the proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Listen address, ports and timeouts, shared with Part 1
+    pub listen: cli::ListenConfig,
+    /// Frames queued for a destination's writer before it is dropped as too slow
+    pub dest_queue_capacity: usize,
+    /// Destinations allowed to be connected at once; `None` is unlimited
+    pub max_destinations: Option<usize>,
+    /// Sources allowed to be connected at once; `None` is unlimited
+    pub max_sources: Option<usize>,
+    /// Sources and destinations allowed from one IP at once; `None` is unlimited
+    pub max_conns_per_ip: Option<usize>,
+    /// Unexpected bytes a destination may send before it is disconnected
+    pub max_dest_inbound: u64,
+    /// Wait for a destination to send a ready byte before broadcasting to it
+    pub dest_handshake: bool,
+    /// Fan frames out to destinations in ascending id order
+    pub deterministic_order: bool,
+    /// How long a frame may wait for a destination's writer; `None` waits forever
+    pub frame_deadline: Option<Duration>,
+    /// Frames with a bad checksum tolerated in a row before a source is dropped
+    pub max_consecutive_invalid: u32,
+    /// Bytes per second the broadcaster forwards; `None` is unlimited
+    pub max_throughput: Option<u64>,
+    /// Bytes skipped looking for the next frame after a framing error; 0 disconnects
+    pub resync_limit: usize,
+    /// Largest payload length accepted from a source
+    pub max_payload: usize,
+    /// Magic bytes a frame may start with
+    pub magics: Vec<u8>,
+    /// Validate the checksum of non-sensitive frames too
+    pub audit_checksums: bool,
+    /// Payload length every frame must have; `None` allows any length
+    pub exact_payload_len: Option<usize>,
+    /// Patterns a payload is dropped for matching; empty forwards everything
+    pub deny_patterns: Vec<DenyPattern>,
+    /// Multiple of the longest payload forwarded so far a payload may not exceed;
+    /// `None` disables the check
+    pub payload_anomaly_factor: Option<u64>,
+    /// Shortest gap between two frames forwarded from one source; `None` sends at once
+    pub min_frame_gap: Option<Duration>,
+    /// How long a frame's header may take once its first byte arrives; `None` waits
+    pub header_timeout: Option<Duration>,
+    /// How long any connection may stay open; `None` is unlimited
+    pub max_conn_lifetime: Option<Duration>,
+    /// Kernel receive buffer requested for source sockets; `None` keeps the default
+    pub source_rcvbuf: Option<usize>,
+    /// Frames each source may queue ahead of the broadcaster; `None` sends directly
+    pub source_queue_depth: Option<usize>,
+    /// Sink sent a copy of every frame before it is broadcast; `None` disables it
+    pub audit_sink: Option<SocketAddr>,
+    /// Forward frames while the audit sink is unreachable instead of dropping them
+    pub audit_fail_open: bool,
+    /// How often a metrics summary is logged; `None` disables it
+    pub metrics_interval: Option<Duration>,
+    /// How often connects and disconnects are summarized; `None` logs each one
+    pub log_summary_interval: Option<Duration>,
+    /// StatsD server metrics are pushed to; `None` disables it
+    pub statsd: Option<SocketAddr>,
+    /// How often metrics are pushed to the StatsD server
+    pub statsd_interval: Duration,
+    /// Address the admin and control listeners bind to, separately from the proxy ports
+    pub admin_addr: IpAddr,
+    /// Port serving the read-only metrics endpoint; `None` disables it
+    pub admin_port: Option<u16>,
+    /// Port accepting plain-text control commands; `None` disables it
+    pub control_port: Option<u16>,
+    /// File lifecycle events are appended to as JSON lines; `None` disables it
+    pub event_log: Option<PathBuf>,
+    /// Shortest gap between two warnings of the same kind; `None` logs every warning
+    pub log_interval: Option<Duration>,
+    /// Shell command that must succeed before connections are accepted
+    pub startup_hook: Option<String>,
+    /// Shell command run after a graceful shutdown
+    pub shutdown_hook: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: cli::ListenConfig::default(),
+            dest_queue_capacity: DEFAULT_DEST_QUEUE_CAPACITY,
+            max_destinations: None,
+            max_sources: None,
+            max_conns_per_ip: None,
+            max_dest_inbound: DEFAULT_MAX_DEST_INBOUND,
+            dest_handshake: false,
+            deterministic_order: false,
+            frame_deadline: None,
+            max_consecutive_invalid: 0,
+            max_throughput: None,
+            resync_limit: 0,
+            max_payload: MAX_PAYLOAD,
+            magics: vec![MAGIC],
+            audit_checksums: false,
+            exact_payload_len: None,
+            deny_patterns: Vec::new(),
+            payload_anomaly_factor: None,
+            min_frame_gap: None,
+            header_timeout: None,
+            max_conn_lifetime: None,
+            source_rcvbuf: None,
+            source_queue_depth: None,
+            audit_sink: None,
+            audit_fail_open: false,
+            metrics_interval: Some(DEFAULT_METRICS_INTERVAL),
+            log_summary_interval: None,
+            statsd: None,
+            statsd_interval: DEFAULT_STATSD_INTERVAL,
+            admin_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            admin_port: None,
+            control_port: None,
+            event_log: None,
+            log_interval: Some(log_limit::DEFAULT_INTERVAL),
+            startup_hook: None,
+            shutdown_hook: None,
+        }
+    }
+}
+
+impl Config {
+    /// Parser settings applied to every source.
+    pub fn parse_config(&self) -> ParseConfig {
+        ParseConfig {
+            max_payload: self.max_payload,
+            magics: self.magics.clone(),
+            audit_checksums: self.audit_checksums,
+            ..ParseConfig::default()
+        }
+    }
+
+    /// Address the admin listener binds to, if it is enabled.
+    pub fn admin_socket_addr(&self) -> Option<SocketAddr> {
+        self.admin_port.map(|port| SocketAddr::new(self.admin_addr, port))
+    }
+
+    /// Address the control listener binds to, if it is enabled.
+    pub fn control_socket_addr(&self) -> Option<SocketAddr> {
+        self.control_port.map(|port| SocketAddr::new(self.admin_addr, port))
+    }
+}
+
+/// Parses command-line arguments, excluding the program name.
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config, ConfigError> {
+    let mut config = Config::default();
+    let mut args = args.into_iter();
+
+    // Magics given on the command line replace the default rather than adding to it
+    let mut magics = Vec::new();
+
+    while let Some(option) = args.next() {
+        if config.listen.apply(&option, &mut args)? {
+            continue;
+        }
+        match option.as_str() {
+            "--dest-queue-capacity" => {
+                config.dest_queue_capacity = parse_value(&option, args.next())?;
+                if config.dest_queue_capacity == 0 {
+                    return Err(ConfigError::InvalidValue {
+                        option,
+                        value: "0".to_string(),
+                    });
+                }
+            }
+            "--max-destinations" => {
+                let max: usize = parse_value(&option, args.next())?;
+                config.max_destinations = (max > 0).then_some(max);
+            }
+            "--max-sources" => {
+                let max: usize = parse_value(&option, args.next())?;
+                config.max_sources = (max > 0).then_some(max);
+            }
+            "--max-conns-per-ip" => {
+                let max: usize = parse_value(&option, args.next())?;
+                config.max_conns_per_ip = (max > 0).then_some(max);
+            }
+            "--max-dest-inbound" => config.max_dest_inbound = parse_value(&option, args.next())?,
+            "--dest-handshake" => config.dest_handshake = true,
+            "--deterministic-order" => config.deterministic_order = true,
+            "--frame-deadline-ms" => {
+                let millis: u64 = parse_value(&option, args.next())?;
+                config.frame_deadline = (millis > 0).then(|| Duration::from_millis(millis));
+            }
+            "--max-consecutive-invalid" => {
+                config.max_consecutive_invalid = parse_value(&option, args.next())?
+            }
+            "--max-throughput" => {
+                let bytes_per_sec: u64 = parse_value(&option, args.next())?;
+                config.max_throughput = (bytes_per_sec > 0).then_some(bytes_per_sec);
+            }
+            "--source-rcvbuf" => {
+                let bytes: usize = parse_value(&option, args.next())?;
+                config.source_rcvbuf = (bytes > 0).then_some(bytes);
+            }
+            "--source-queue-depth" => {
+                let depth: usize = parse_value(&option, args.next())?;
+                config.source_queue_depth = (depth > 0).then_some(depth);
+            }
+            "--audit-sink" => config.audit_sink = Some(parse_value(&option, args.next())?),
+            "--audit-fail-open" => config.audit_fail_open = true,
+            "--resync-limit" => config.resync_limit = parse_value(&option, args.next())?,
+            "--max-payload" => config.max_payload = parse_value(&option, args.next())?,
+            "--magic" => magics.push(parse_byte(&option, args.next())?),
+            "--audit-checksums" => config.audit_checksums = true,
+            "--min-frame-gap-ms" => {
+                let millis: u64 = parse_value(&option, args.next())?;
+                config.min_frame_gap = (millis > 0).then(|| Duration::from_millis(millis));
+            }
+            "--header-timeout-ms" => {
+                let millis: u64 = parse_value(&option, args.next())?;
+                config.header_timeout = (millis > 0).then(|| Duration::from_millis(millis));
+            }
+            "--deny-pattern" => config.deny_patterns.push(parse_value(&option, args.next())?),
+            "--payload-anomaly-factor" => {
+                let factor: u64 = parse_value(&option, args.next())?;
+                config.payload_anomaly_factor = (factor > 0).then_some(factor);
+            }
+            "--exact-payload-len" => {
+                config.exact_payload_len = Some(parse_value(&option, args.next())?)
+            }
+            "--admin-port" => config.admin_port = Some(parse_value(&option, args.next())?),
+            "--control-port" => config.control_port = Some(parse_value(&option, args.next())?),
+            "--event-log" => config.event_log = Some(parse_value(&option, args.next())?),
+            "--startup-hook" => config.startup_hook = Some(parse_value(&option, args.next())?),
+            "--shutdown-hook" => config.shutdown_hook = Some(parse_value(&option, args.next())?),
+            "--admin-addr" => config.admin_addr = parse_value(&option, args.next())?,
+            "--metrics-interval" => config.metrics_interval = parse_seconds(&option, args.next())?,
+            "--log-summary-interval" => {
+                config.log_summary_interval = parse_seconds(&option, args.next())?
+            }
+            "--statsd" => config.statsd = Some(parse_value(&option, args.next())?),
+            "--statsd-interval" => {
+                let secs: u64 = parse_value(&option, args.next())?;
+                if secs == 0 {
+                    return Err(ConfigError::InvalidValue {
+                        option,
+                        value: secs.to_string(),
+                    });
+                }
+                config.statsd_interval = Duration::from_secs(secs);
+            }
+            "--log-interval" => config.log_interval = parse_seconds(&option, args.next())?,
+            "--max-conn-lifetime" => {
+                config.max_conn_lifetime = parse_seconds(&option, args.next())?
+            }
+            _ => return Err(ConfigError::UnknownArgument(option)),
+        }
+    }
+
+    if !magics.is_empty() {
+        config.magics = magics;
+    }
+    config.listen.validate()?;
+    Ok(config)
+}
+
+/// Parses a byte given in decimal or as `0x`-prefixed hex.
+fn parse_byte(option: &str, value: Option<String>) -> Result<u8, ConfigError> {
+    let value = value.ok_or_else(|| ConfigError::MissingValue(option.to_string()))?;
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| ConfigError::InvalidValue {
+        option: option.to_string(),
+        value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, ConfigError> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn defaults_match_original_ports() {
+        let config = parse(&[]).unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.listen.source_addr().to_string(), "0.0.0.0:33333");
+        assert_eq!(config.listen.dest_addr().to_string(), "0.0.0.0:44444");
+    }
+
+    #[test]
+    fn options_override_defaults() {
+        // Shared options and Part 2 options can be mixed
+        let config = parse(&["--source-port", "4000", "--max-consecutive-invalid", "3"]).unwrap();
+        assert_eq!(config.listen.source_addr().to_string(), "0.0.0.0:4000");
+        assert_eq!(config.max_consecutive_invalid, 3);
+
+        let config = parse(&["--dest-queue-capacity", "16"]).unwrap();
+        assert_eq!(config.dest_queue_capacity, 16);
+        assert!(parse(&["--dest-queue-capacity", "0"]).is_err());
+
+        let config = parse(&["--max-destinations", "8"]).unwrap();
+        assert_eq!(config.max_destinations, Some(8));
+        assert_eq!(parse(&["--max-destinations", "0"]).unwrap().max_destinations, None);
+
+        // Source and destination limits are set independently
+        let config = parse(&["--max-sources", "4"]).unwrap();
+        assert_eq!(config.max_sources, Some(4));
+        assert_eq!(config.max_destinations, None);
+        assert_eq!(parse(&["--max-conns-per-ip", "3"]).unwrap().max_conns_per_ip, Some(3));
+        assert_eq!(parse(&["--max-conns-per-ip", "0"]).unwrap().max_conns_per_ip, None);
+
+        assert_eq!(parse(&[]).unwrap().max_dest_inbound, DEFAULT_MAX_DEST_INBOUND);
+        let config = parse(&["--max-dest-inbound", "0"]).unwrap();
+        assert_eq!(config.max_dest_inbound, 0);
+
+        assert!(!parse(&[]).unwrap().dest_handshake);
+        assert!(parse(&["--dest-handshake"]).unwrap().dest_handshake);
+        assert!(!parse(&[]).unwrap().deterministic_order);
+        assert!(parse(&["--deterministic-order"]).unwrap().deterministic_order);
+
+        let config = parse(&["--max-throughput", "1000000"]).unwrap();
+        assert_eq!(config.max_throughput, Some(1_000_000));
+        assert_eq!(parse(&["--max-throughput", "0"]).unwrap().max_throughput, None);
+
+        // The admin endpoint is off unless a port is given, and stays on localhost
+        // even when the proxy binds elsewhere
+        assert_eq!(parse(&[]).unwrap().admin_socket_addr(), None);
+        let config = parse(&["--bind-addr", "0.0.0.0", "--admin-port", "9090"]).unwrap();
+        assert_eq!(config.admin_socket_addr().unwrap().to_string(), "127.0.0.1:9090");
+        assert_eq!(config.control_socket_addr(), None);
+        let config = parse(&["--control-port", "9091", "--admin-addr", "10.0.0.1"]).unwrap();
+        assert_eq!(config.control_socket_addr().unwrap().to_string(), "10.0.0.1:9091");
+
+        let config = parse(&["--event-log", "/var/log/wirestorm2/events.jsonl"]).unwrap();
+        assert_eq!(config.event_log, Some(PathBuf::from("/var/log/wirestorm2/events.jsonl")));
+
+        let config = parse(&["--startup-hook", "consul services register x.json"]).unwrap();
+        assert_eq!(config.startup_hook.as_deref(), Some("consul services register x.json"));
+        assert_eq!(config.shutdown_hook, None);
+
+        let config = parse(&["--resync-limit", "4096"]).unwrap();
+        assert_eq!(config.resync_limit, 4096);
+
+        let config = parse(&["--source-rcvbuf", "1048576"]).unwrap();
+        assert_eq!(config.source_rcvbuf, Some(1 << 20));
+        assert_eq!(parse(&["--source-rcvbuf", "0"]).unwrap().source_rcvbuf, None);
+        let config = parse(&["--source-queue-depth", "32"]).unwrap();
+        assert_eq!(config.source_queue_depth, Some(32));
+        assert_eq!(parse(&["--source-queue-depth", "0"]).unwrap().source_queue_depth, None);
+
+        assert_eq!(parse(&[]).unwrap().audit_sink, None);
+        let config = parse(&["--statsd", "10.0.0.6:8125", "--statsd-interval", "5"]).unwrap();
+        assert_eq!(config.statsd.unwrap().to_string(), "10.0.0.6:8125");
+        assert_eq!(config.statsd_interval, Duration::from_secs(5));
+        assert_eq!(parse(&[]).unwrap().statsd_interval, DEFAULT_STATSD_INTERVAL);
+        assert!(parse(&["--statsd-interval", "0"]).is_err());
+        let config = parse(&["--audit-sink", "10.0.0.5:7000", "--audit-fail-open"]).unwrap();
+        assert_eq!(config.audit_sink.unwrap().to_string(), "10.0.0.5:7000");
+        assert!(config.audit_fail_open);
+
+        let config = parse(&["--max-payload", "512"]).unwrap();
+        assert_eq!(config.parse_config().max_payload, 512);
+        assert_eq!(Config::default().parse_config(), ParseConfig::default());
+
+        assert!(parse(&["--audit-checksums"]).unwrap().parse_config().audit_checksums);
+
+        assert_eq!(parse(&[]).unwrap().exact_payload_len, None);
+        let config = parse(&["--exact-payload-len", "0"]).unwrap();
+        assert_eq!(config.exact_payload_len, Some(0));
+
+        // Deny patterns accumulate, and a malformed one is rejected
+        assert!(parse(&[]).unwrap().deny_patterns.is_empty());
+        let config = parse(&["--deny-pattern", "0xBEEF", "--deny-pattern", "drop*"]).unwrap();
+        assert_eq!(
+            config.deny_patterns,
+            vec![
+                DenyPattern::Bytes(vec![0xBE, 0xEF]),
+                DenyPattern::Glob(b"drop*".to_vec()),
+            ]
+        );
+        assert!(parse(&["--deny-pattern", "0xBEE"]).is_err());
+
+        assert_eq!(parse(&[]).unwrap().payload_anomaly_factor, None);
+        let config = parse(&["--payload-anomaly-factor", "4"]).unwrap();
+        assert_eq!(config.payload_anomaly_factor, Some(4));
+        assert_eq!(parse(&["--payload-anomaly-factor", "0"]).unwrap().payload_anomaly_factor, None);
+    }
+
+    #[test]
+    fn magics_replace_the_default_and_accumulate() {
+        assert_eq!(parse(&[]).unwrap().magics, vec![MAGIC]);
+
+        let config = parse(&["--magic", "0xCC", "--magic", "205"]).unwrap();
+        assert_eq!(config.magics, vec![0xCC, 0xCD]);
+        assert_eq!(config.parse_config().magics, vec![0xCC, 0xCD]);
+
+        // A single new magic stops the old one being accepted
+        assert_eq!(parse(&["--magic", "0xab"]).unwrap().magics, vec![0xAB]);
+
+        for bad in ["0x100", "256", "0xZZ", "CC"] {
+            assert_eq!(
+                parse(&["--magic", bad]),
+                Err(ConfigError::InvalidValue {
+                    option: "--magic".to_string(),
+                    value: bad.to_string(),
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn durations_are_in_seconds_and_zero_disables() {
+        let config = parse(&[]).unwrap();
+        assert_eq!(config.metrics_interval, Some(DEFAULT_METRICS_INTERVAL));
+        let config = parse(&["--metrics-interval", "0"]).unwrap();
+        assert_eq!(config.metrics_interval, None);
+
+        assert_eq!(config.log_summary_interval, None);
+        let config = parse(&["--log-summary-interval", "10"]).unwrap();
+        assert_eq!(config.log_summary_interval, Some(Duration::from_secs(10)));
+
+        assert_eq!(config.log_interval, Some(log_limit::DEFAULT_INTERVAL));
+        let config = parse(&["--log-interval", "10"]).unwrap();
+        assert_eq!(config.log_interval, Some(Duration::from_secs(10)));
+        assert_eq!(parse(&["--log-interval", "0"]).unwrap().log_interval, None);
+
+        assert_eq!(config.max_conn_lifetime, None);
+        let config = parse(&["--max-conn-lifetime", "3600"]).unwrap();
+        assert_eq!(config.max_conn_lifetime, Some(Duration::from_secs(3600)));
+
+        // Per-frame durations are given in milliseconds
+        let config = parse(&["--min-frame-gap-ms", "10"]).unwrap();
+        assert_eq!(config.min_frame_gap, Some(Duration::from_millis(10)));
+        assert_eq!(parse(&["--min-frame-gap-ms", "0"]).unwrap().min_frame_gap, None);
+        let config = parse(&["--header-timeout-ms", "500"]).unwrap();
+        assert_eq!(config.header_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(parse(&["--header-timeout-ms", "0"]).unwrap().header_timeout, None);
+        let config = parse(&["--frame-deadline-ms", "5"]).unwrap();
+        assert_eq!(config.frame_deadline, Some(Duration::from_millis(5)));
+        assert_eq!(parse(&["--frame-deadline-ms", "0"]).unwrap().frame_deadline, None);
+    }
+
+    #[test]
+    fn shared_options_are_validated() {
+        assert_eq!(parse(&["--dest-port", "33333"]), Err(ConfigError::SamePort(33333)));
+    }
+
+    #[test]
+    fn bad_arguments_are_rejected() {
+        assert_eq!(
+            parse(&["--max-sources"]),
+            Err(ConfigError::MissingValue("--max-sources".to_string()))
+        );
+        assert_eq!(
+            parse(&["--dest-queue-capacity", "0"]),
+            Err(ConfigError::InvalidValue {
+                option: "--dest-queue-capacity".to_string(),
+                value: "0".to_string(),
+            })
+        );
+        assert_eq!(
+            parse(&["--verbose"]),
+            Err(ConfigError::UnknownArgument("--verbose".to_string()))
+        );
+    }
+}
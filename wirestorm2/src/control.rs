@@ -0,0 +1,226 @@
+//! Admin Control Port
+//!
+//! A plain-text, line-based interface for querying a running proxy, meant to be
+//! driven with `nc`. Each line is a command; each reply is zero or more lines
+//! followed by an empty line, so replies can be told apart in a long session.
+//!
+//! - `stats`: uptime and the metrics counters
+//! - `list-dest`: connected destinations, one `#id addr` per line
+//! - `list-src`: connected sources, one `#id addr frames=N bytes=N resyncs=N` per line
+//! - `drain <id>`: stop sending new frames to a destination and close it once the
+//!   frames already queued for it are written
+//! - `recent`: connections recently cut off on an error, most recent first, one
+//!   `#id role addr at_ms=N kind=K error=E` per line
+//! - `quit`: close the connection
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::metrics::Metrics;
+use crate::recent;
+use crate::shutdown::{self, ShutdownFlag};
+use crate::{DestinationList, SourceRegistry};
+
+/// Longest command line accepted before the connection is closed
+const MAX_LINE: usize = 1024;
+
+/// Shared proxy state the control port reports on.
+#[derive(Clone)]
+pub struct ControlState {
+    pub started: Instant,
+    pub metrics: Arc<Metrics>,
+    pub destinations: DestinationList,
+    pub sources: SourceRegistry,
+}
+
+/// Runs `command` and returns its reply, or `None` if the connection should close.
+pub fn execute(command: &str, state: &ControlState) -> Option<String> {
+    let reply = match command.trim() {
+        "stats" => {
+            let snapshot = state.metrics.snapshot();
+            [
+                ("uptime_secs", state.started.elapsed().as_secs()),
+                ("active_sources", snapshot.active_sources),
+                ("active_destinations", snapshot.active_destinations),
+                ("messages_forwarded", snapshot.messages_forwarded),
+                ("bytes_forwarded", snapshot.bytes_forwarded),
+                ("checksum_drops", snapshot.checksum_drops),
+                ("length_drops", snapshot.length_drops),
+                ("denied_frames", snapshot.denied_frames),
+                ("anomaly_drops", snapshot.anomaly_drops),
+                ("max_payload", snapshot.max_payload),
+                ("deadline_skips", snapshot.deadline_skips),
+                ("audit_drops", snapshot.audit_drops),
+                ("resyncs", snapshot.resyncs),
+                ("clean_disconnects", snapshot.clean_disconnects),
+                ("error_disconnects", snapshot.error_disconnects),
+                ("sources_connected", snapshot.sources_connected),
+                ("destinations_connected", snapshot.destinations_connected),
+            ]
+            .iter()
+            .map(|(name, value)| format!("{} {}\n", name, value))
+            .chain(snapshot.parse_errors.by_kind().iter().map(|(kind, value)| {
+                format!("parse_errors_{} {}\n", kind, value)
+            }))
+            .collect::<String>()
+        }
+        "list-dest" => {
+            let destinations = state.destinations.lock().unwrap();
+            let peers = destinations
+                .iter()
+                .map(|dest| (dest.id, describe_peer(dest.stream.peer_addr().ok())));
+            list_peers(peers.collect())
+        }
+        "list-src" => {
+            let sources = state.sources.lock().unwrap();
+            let peers = sources.iter().map(|(&id, source)| {
+                let totals = source.counters.totals();
+                (id, format!("{} {}", describe_peer(source.addr), totals))
+            });
+            list_peers(peers.collect())
+        }
+        "recent" => recent::entries()
+            .iter()
+            .map(|entry| entry.to_line() + "\n")
+            .collect(),
+        "quit" => return None,
+        "" => String::new(),
+        other if other.starts_with("drain ") => drain(&other["drain ".len()..], state),
+        other => format!("ERR unknown command '{}'\n", other),
+    };
+    Some(reply + "\n")
+}
+
+/// Removes the destination with the id in `arg` from the broadcast list. Its writer
+/// sends what is already queued and exits, and the destination is then closed.
+fn drain(arg: &str, state: &ControlState) -> String {
+    let Ok(id) = arg.trim().trim_start_matches('#').parse::<u64>() else {
+        return format!("ERR invalid destination id '{}'\n", arg.trim());
+    };
+    if crate::remove_destination(&state.destinations, id) {
+        log::info!("Draining destination #{} on request from the control port", id);
+        format!("OK draining #{}\n", id)
+    } else {
+        format!("ERR no destination #{}\n", id)
+    }
+}
+
+/// Formats clients as `#id description` lines in id order.
+fn list_peers(mut peers: Vec<(u64, String)>) -> String {
+    peers.sort_by_key(|&(id, _)| id);
+    peers
+        .into_iter()
+        .map(|(id, description)| format!("#{} {}\n", id, description))
+        .collect()
+}
+
+/// Formats a client's address, which may not be known.
+fn describe_peer(addr: Option<SocketAddr>) -> String {
+    match addr {
+        Some(addr) => addr.to_string(),
+        None => "(unknown addr)".to_string(),
+    }
+}
+
+/// Serves commands on one control connection until it closes, sends `quit`, or
+/// shutdown is requested.
+pub fn handle_connection(
+    mut stream: TcpStream,
+    state: ControlState,
+    shutdown: ShutdownFlag,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(shutdown::POLL_INTERVAL))?;
+
+    let mut line = Vec::new();
+    let mut buf = [0u8; 256];
+    while !shutdown::requested(&shutdown) {
+        let n = match stream.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        for &byte in &buf[..n] {
+            if byte != b'\n' {
+                line.push(byte);
+                if line.len() > MAX_LINE {
+                    stream.write_all(b"ERR line too long\n\n")?;
+                    return Ok(());
+                }
+                continue;
+            }
+
+            let command = String::from_utf8_lossy(&line).into_owned();
+            line.clear();
+            match execute(&command, &state) {
+                Some(reply) => stream.write_all(reply.as_bytes())?,
+                None => return Ok(()),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SourceEntry;
+    use crate::metrics::SourceCounters;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    fn state() -> ControlState {
+        ControlState {
+            started: Instant::now(),
+            metrics: Arc::new(Metrics::default()),
+            destinations: Arc::new(Mutex::new(Vec::new())),
+            sources: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    #[test]
+    fn commands_report_state() {
+        let state = state();
+        state.metrics.record_source_connected();
+        state.metrics.record_forwarded(13);
+        {
+            let counters = Arc::new(SourceCounters::default());
+            counters.record_frame(10);
+            counters.record_frame(20);
+            let mut sources = state.sources.lock().unwrap();
+            let addr = Some("10.0.0.2:5000".parse().unwrap());
+            sources.insert(3, SourceEntry { addr, counters });
+            let counters = Arc::new(SourceCounters::default());
+            sources.insert(1, SourceEntry { addr: None, counters });
+        }
+
+        let stats = execute("stats", &state).unwrap();
+        assert!(stats.starts_with("uptime_secs 0\n"));
+        assert!(stats.contains("\nactive_sources 1\n"));
+        assert!(stats.contains("\nbytes_forwarded 13\n"));
+        assert!(stats.contains("\nparse_errors_bad_magic 0\n"));
+        assert!(stats.ends_with("\n\n"));
+
+        assert_eq!(
+            execute("list-src\r", &state).unwrap(),
+            "#1 (unknown addr) frames=0 bytes=0 resyncs=0\n\
+             #3 10.0.0.2:5000 frames=2 bytes=30 resyncs=0\n\n"
+        );
+        assert_eq!(execute("list-dest", &state).unwrap(), "\n");
+        assert_eq!(
+            execute("drop-all", &state).unwrap(),
+            "ERR unknown command 'drop-all'\n\n"
+        );
+        assert_eq!(execute("drain 7", &state).unwrap(), "ERR no destination #7\n\n");
+        assert_eq!(
+            execute("drain seven", &state).unwrap(),
+            "ERR invalid destination id 'seven'\n\n"
+        );
+        assert_eq!(execute("quit", &state), None);
+    }
+}
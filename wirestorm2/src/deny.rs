@@ -0,0 +1,104 @@
+//! Payload Deny Patterns
+//!
+//! Content filtering for deployments that must keep certain payloads from reaching
+//! the destinations. Each `--deny-pattern` is either a `0x`-prefixed hex byte
+//! sequence, matched anywhere in the payload, or a glob matched against the whole
+//! payload, where `*` stands for any run of bytes and `?` for any single byte. A frame
+//! whose payload matches any pattern is dropped by its source's handler and counted.
+
+use std::str::FromStr;
+
+/// One configured pattern a payload may be denied by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DenyPattern {
+    /// Bytes that may appear anywhere in the payload
+    Bytes(Vec<u8>),
+    /// Glob the whole payload must match
+    Glob(Vec<u8>),
+}
+
+impl DenyPattern {
+    /// Returns whether `payload` matches the pattern.
+    pub fn matches(&self, payload: &[u8]) -> bool {
+        match self {
+            DenyPattern::Bytes(bytes) => payload.windows(bytes.len()).any(|w| w == bytes),
+            DenyPattern::Glob(glob) => glob_matches(glob, payload),
+        }
+    }
+}
+
+impl FromStr for DenyPattern {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, ()> {
+        let Some(hex) = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) else {
+            return match value {
+                "" => Err(()),
+                glob => Ok(DenyPattern::Glob(glob.as_bytes().to_vec())),
+            };
+        };
+        if hex.is_empty() || hex.len() % 2 != 0 || !hex.is_ascii() {
+            return Err(());
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| ()))
+            .collect::<Result<_, _>>()?;
+        Ok(DenyPattern::Bytes(bytes))
+    }
+}
+
+/// Matches `text` against `glob`, backtracking to the last `*` on a mismatch.
+fn glob_matches(glob: &[u8], text: &[u8]) -> bool {
+    let (mut g, mut t) = (0, 0);
+    // Glob index after the last `*`, and the text index it is retried from
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match glob.get(g) {
+            Some(b'*') => {
+                star = Some((g + 1, t));
+                g += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                g += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((after, resume)) => {
+                    g = after;
+                    t = resume + 1;
+                    star = Some((after, resume + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(value: &str) -> DenyPattern {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn hex_patterns_match_anywhere_and_globs_match_the_whole_payload() {
+        assert_eq!(pattern("0xDEad"), DenyPattern::Bytes(vec![0xDE, 0xAD]));
+        assert!(pattern("0xDEAD").matches(b"\x00\xDE\xAD\x00"));
+        assert!(!pattern("0xDEAD").matches(b"\xDE\x00\xAD"));
+
+        assert!(pattern("secret*").matches(b"secret plans"));
+        assert!(!pattern("secret*").matches(b"not secret"));
+        assert!(pattern("*pass?ord*").matches(b"my password is"));
+        assert!(pattern("a*b*c").matches(b"aXbYbZc"));
+        assert!(!pattern("a*b*c").matches(b"aXbYbZ"));
+        assert!(pattern("*").matches(b""));
+
+        for bad in ["", "0x", "0xABC", "0xZZ", "0xé0"] {
+            assert!(bad.parse::<DenyPattern>().is_err(), "{:?} parsed", bad);
+        }
+    }
+}
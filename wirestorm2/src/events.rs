@@ -0,0 +1,264 @@
+//! Structured Event Log
+//!
+//! Connection lifecycle events written as JSON lines to a dedicated audit file, kept
+//! apart from the diagnostic log so they can be processed without parsing mixed
+//! output. Every accepted client gets a `connect` and a `disconnect` record; `drop`
+//! records why the proxy cut a client off, `reject` a client that failed set-up, and
+//! `limit_hit` a connection refused by a connection cap. Like [`crate::log_limit`],
+//! the log is process-wide: [`record`] does nothing until [`install`] is called.
+
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::log_limit;
+
+/// Kind of lifecycle event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// A client was accepted and registered
+    Connect,
+    /// A registered client's connection ended, for whatever reason
+    Disconnect,
+    /// The proxy decided to cut a client off
+    Drop,
+    /// A client failed set-up and was never registered
+    Reject,
+    /// A connection was refused because a connection cap was reached
+    LimitHit,
+}
+
+impl EventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Connect => "connect",
+            EventKind::Disconnect => "disconnect",
+            EventKind::Drop => "drop",
+            EventKind::Reject => "reject",
+            EventKind::LimitHit => "limit_hit",
+        }
+    }
+}
+
+/// Which side of the proxy a client is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Source,
+    Destination,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Source => "source",
+            Role::Destination => "destination",
+        }
+    }
+}
+
+/// A single lifecycle event.
+#[derive(Debug, Clone, Copy)]
+pub struct Event<'a> {
+    pub kind: EventKind,
+    pub role: Role,
+    /// Client id, if one was assigned before the event
+    pub id: Option<u64>,
+    pub addr: Option<SocketAddr>,
+    pub reason: Option<&'a str>,
+}
+
+impl Event<'_> {
+    /// Formats the event as one JSON object, without a trailing newline.
+    /// `timestamp_ms` is milliseconds since the Unix epoch.
+    pub fn to_json(self, timestamp_ms: u128) -> String {
+        let mut json = format!(
+            "{{\"timestamp_ms\":{},\"event\":\"{}\",\"role\":\"{}\"",
+            timestamp_ms,
+            self.kind.as_str(),
+            self.role.as_str()
+        );
+        match self.id {
+            Some(id) => {
+                let _ = write!(json, ",\"id\":{}", id);
+            }
+            None => json.push_str(",\"id\":null"),
+        }
+        match self.addr {
+            Some(addr) => {
+                let _ = write!(json, ",\"addr\":\"{}\"", addr);
+            }
+            None => json.push_str(",\"addr\":null"),
+        }
+        match self.reason {
+            Some(reason) => {
+                json.push_str(",\"reason\":\"");
+                escape_into(&mut json, reason);
+                json.push('"');
+            }
+            None => json.push_str(",\"reason\":null"),
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// Appends `text` to `json` with JSON string escaping.
+fn escape_into(json: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+}
+
+/// An append-only JSON-lines event file.
+pub struct EventLog {
+    out: Mutex<LineWriter<File>>,
+}
+
+impl EventLog {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(EventLog {
+            out: Mutex::new(LineWriter::new(file)),
+        })
+    }
+
+    /// Appends `event` as one line, stamped with the current time.
+    pub fn write(&self, event: &Event) -> io::Result<()> {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis());
+        let line = event.to_json(timestamp_ms) + "\n";
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        out.write_all(line.as_bytes())
+    }
+}
+
+/// Event log used by [`record`], registered by [`install`]
+static EVENT_LOG: OnceLock<EventLog> = OnceLock::new();
+
+/// Sends every later [`record`] call to `log`. Only the first log installed is used.
+pub fn install(log: EventLog) {
+    let _ = EVENT_LOG.set(log);
+}
+
+/// Records an event in the installed event log, if there is one.
+pub fn record(
+    kind: EventKind,
+    role: Role,
+    id: Option<u64>,
+    addr: Option<SocketAddr>,
+    reason: Option<&str>,
+) {
+    let Some(log) = EVENT_LOG.get() else {
+        return;
+    };
+    let event = Event {
+        kind,
+        role,
+        id,
+        addr,
+        reason,
+    };
+    if let Err(e) = log.write(&event) {
+        log_limit::warn("event log", format_args!("Failed to write event log: {}", e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_formatted_as_json() {
+        let event = Event {
+            kind: EventKind::Drop,
+            role: Role::Source,
+            id: Some(4),
+            addr: Some("10.0.0.2:5000".parse().unwrap()),
+            reason: Some("bad magic byte 0x00, \"quoted\"\n"),
+        };
+        assert_eq!(
+            event.to_json(1_700_000_000_000),
+            "{\"timestamp_ms\":1700000000000,\"event\":\"drop\",\"role\":\"source\",\"id\":4,\
+             \"addr\":\"10.0.0.2:5000\",\"reason\":\"bad magic byte 0x00, \\\"quoted\\\"\\n\"}"
+        );
+
+        let event = Event {
+            kind: EventKind::LimitHit,
+            role: Role::Destination,
+            id: None,
+            addr: None,
+            reason: Some("tab\there, bell\u{7}"),
+        };
+        assert_eq!(
+            event.to_json(0),
+            "{\"timestamp_ms\":0,\"event\":\"limit_hit\",\"role\":\"destination\",\"id\":null,\
+             \"addr\":null,\"reason\":\"tab\\there, bell\\u0007\"}"
+        );
+    }
+
+    #[test]
+    fn event_log_appends_one_record_per_line() {
+        let name = format!("wirestorm2-events-{}.jsonl", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let event = |kind, reason| Event {
+            kind,
+            role: Role::Destination,
+            id: Some(0),
+            addr: Some(addr),
+            reason,
+        };
+        {
+            let log = EventLog::open(&path).unwrap();
+            log.write(&event(EventKind::Connect, None)).unwrap();
+            log.write(&event(EventKind::Drop, Some("send queue full"))).unwrap();
+        }
+        // Reopening appends rather than truncating
+        EventLog::open(&path)
+            .unwrap()
+            .write(&event(EventKind::Disconnect, Some("connection closed")))
+            .unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let records: Vec<&str> = contents.lines().collect();
+        assert_eq!(records.len(), 3);
+
+        // Strip the timestamp, which differs per run
+        let tail = |record: &str| record.split_once(",\"event\"").unwrap().1.to_string();
+        assert!(records.iter().all(|record| record.starts_with("{\"timestamp_ms\":")));
+        assert_eq!(
+            tail(records[0]),
+            ":\"connect\",\"role\":\"destination\",\"id\":0,\"addr\":\"127.0.0.1:4000\",\
+             \"reason\":null}"
+        );
+        assert_eq!(
+            tail(records[1]),
+            ":\"drop\",\"role\":\"destination\",\"id\":0,\"addr\":\"127.0.0.1:4000\",\
+             \"reason\":\"send queue full\"}"
+        );
+        assert_eq!(
+            tail(records[2]),
+            ":\"disconnect\",\"role\":\"destination\",\"id\":0,\"addr\":\"127.0.0.1:4000\",\
+             \"reason\":\"connection closed\"}"
+        );
+    }
+}
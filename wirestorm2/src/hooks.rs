@@ -0,0 +1,21 @@
+//! Lifecycle Hooks
+//!
+//! Optional shell commands run at the edges of the proxy's life, for deployment glue
+//! such as registering with service discovery. The startup hook runs once the
+//! listeners are bound but before any connection is accepted, and the proxy only
+//! starts if it exits zero. The shutdown hook runs after a graceful shutdown has
+//! finished; its failure is only logged, as there is nothing left to abort.
+
+use std::io;
+use std::process::Command;
+
+/// Runs `command` through `sh -c`, waiting for it to finish. A non-zero exit is an
+/// error naming the `hook`.
+pub fn run(hook: &str, command: &str) -> io::Result<()> {
+    log::info!("Running {} hook: {}", hook, command);
+    let status = Command::new("sh").arg("-c").arg(command).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("{} hook failed ({})", hook, status)));
+    }
+    Ok(())
+}
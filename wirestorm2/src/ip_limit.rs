@@ -0,0 +1,70 @@
+//! Per-IP Connection Limit
+//!
+//! Caps how many connections one client machine may hold open at once, counting its
+//! sources and destinations together, so a single host can't take every slot the
+//! global and per-role limits leave. Both acceptors share one map of IP to open
+//! connections; each accepted connection holds an [`IpSlot`] for as long as its
+//! handler runs, and dropping the slot gives the connection back.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+/// Connections open from each IP, shared by the source and destination acceptors.
+#[derive(Clone)]
+pub struct IpLimit {
+    max: Option<usize>,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// One connection counted against its IP, released when dropped.
+pub struct IpSlot {
+    limit: IpLimit,
+    ip: Option<IpAddr>, // None when nothing was counted
+}
+
+impl IpLimit {
+    /// Creates a limit of `max` connections per IP; `None` is unlimited.
+    pub fn new(max: Option<usize>) -> Self {
+        IpLimit {
+            max,
+            connections: Arc::default(),
+        }
+    }
+
+    /// Counts a connection from `addr` against its IP, or returns `None` if that IP
+    /// already has the maximum open. Connections whose address isn't known aren't
+    /// counted.
+    pub fn claim(&self, addr: Option<SocketAddr>) -> Option<IpSlot> {
+        let (Some(max), Some(addr)) = (self.max, addr) else {
+            return Some(IpSlot {
+                limit: self.clone(),
+                ip: None,
+            });
+        };
+
+        let mut connections = self.connections.lock().unwrap();
+        let open = connections.entry(addr.ip()).or_default();
+        if *open >= max {
+            return None;
+        }
+        *open += 1;
+        Some(IpSlot {
+            limit: self.clone(),
+            ip: Some(addr.ip()),
+        })
+    }
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let Some(ip) = self.ip else { return };
+        let mut connections = self.limit.connections.lock().unwrap();
+        if let Some(open) = connections.get_mut(&ip) {
+            *open -= 1;
+            if *open == 0 {
+                connections.remove(&ip);
+            }
+        }
+    }
+}
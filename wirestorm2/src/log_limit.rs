@@ -0,0 +1,178 @@
+//! Log Rate Limiting
+//!
+//! Per-frame warnings (e.g. a flood of bad-checksum frames) can turn logging itself
+//! into a bottleneck. This module coalesces warnings of the same category, so each
+//! category is written at most once per interval, followed by a count of how many
+//! were suppressed. Categories are static strings naming the call site, not the
+//! formatted message, so per-event details such as addresses or checksums can't
+//! defeat the coalescing or grow the limiter's state: it holds one entry per
+//! category, however many distinct messages a client provokes. Messages are passed as
+//! [`fmt::Arguments`] and only formatted when emitted, so a suppressed warning costs
+//! no allocation.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Interval used by the global limiter behind [`warn`] unless [`install`] sets another
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Coalesces log messages by category.
+pub struct LogLimiter {
+    interval: Duration,
+    entries: Mutex<HashMap<&'static str, Entry>>, // Category -> emission state
+}
+
+/// Emission state for a single category
+struct Entry {
+    last_emitted: Instant,
+    suppressed: u64,
+}
+
+impl LogLimiter {
+    /// Creates a limiter that emits each category at most once per `interval`. A zero
+    /// interval emits every message.
+    pub fn new(interval: Duration) -> Self {
+        LogLimiter {
+            interval,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a message in `category` and returns the line to emit, if any.
+    ///
+    /// Returns `None` while the category is being suppressed. The first emission after
+    /// a suppressed run includes how many messages were swallowed.
+    pub fn check(&self, category: &'static str, message: fmt::Arguments) -> Option<String> {
+        let now = Instant::now();
+        let suppressed = {
+            let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            match entries.get_mut(category) {
+                Some(entry) if now.duration_since(entry.last_emitted) < self.interval => {
+                    entry.suppressed += 1;
+                    return None;
+                }
+                Some(entry) => {
+                    let suppressed = entry.suppressed;
+                    entry.last_emitted = now;
+                    entry.suppressed = 0;
+                    suppressed
+                }
+                None => {
+                    entries.insert(
+                        category,
+                        Entry {
+                            last_emitted: now,
+                            suppressed: 0,
+                        },
+                    );
+                    0
+                }
+            }
+        };
+
+        // Formatted outside the lock, and only now the message is known to be emitted
+        if suppressed > 0 {
+            Some(format!("{} (suppressed {} similar messages)", message, suppressed))
+        } else {
+            Some(message.to_string())
+        }
+    }
+
+    /// Logs the message as a warning unless its category is currently suppressed.
+    pub fn warn(&self, category: &'static str, message: fmt::Arguments) {
+        if let Some(line) = self.check(category, message) {
+            log::warn!("{}", line);
+        }
+    }
+}
+
+/// Limiter used by [`warn`], registered by [`install`]
+static LIMITER: OnceLock<LogLimiter> = OnceLock::new();
+
+/// Sends every later [`warn`] call through `limiter`. Only the first limiter
+/// installed is used; call this before anything is logged.
+pub fn install(limiter: LogLimiter) {
+    let _ = LIMITER.set(limiter);
+}
+
+/// Logs a warning in `category` through the process-wide limiter.
+pub fn warn(category: &'static str, message: fmt::Arguments) {
+    LIMITER
+        .get_or_init(|| LogLimiter::new(DEFAULT_INTERVAL))
+        .warn(category, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::thread;
+
+    #[test]
+    fn identical_messages_are_coalesced() {
+        let limiter = LogLimiter::new(Duration::from_millis(100));
+        let message = "Dropping message due to invalid checksum";
+
+        let emitted: Vec<String> = (0..5000)
+            .filter_map(|_| limiter.check("invalid frame", format_args!("{}", message)))
+            .collect();
+        assert_eq!(emitted, vec![message]);
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(
+            limiter.check("invalid frame", format_args!("{}", message)).as_deref(),
+            Some("Dropping message due to invalid checksum (suppressed 4999 similar messages)")
+        );
+    }
+
+    #[test]
+    fn details_do_not_defeat_coalescing() {
+        let limiter = LogLimiter::new(Duration::from_secs(60));
+
+        // Every message differs, as addresses and checksums do under attack
+        let emitted = (0..5000u32)
+            .filter_map(|i| {
+                limiter.check("dropped source", format_args!("Dropping 10.0.0.1:{}", i))
+            })
+            .count();
+        assert_eq!(emitted, 1);
+        assert_eq!(limiter.entries.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn categories_are_limited_separately() {
+        let limiter = LogLimiter::new(Duration::from_secs(60));
+
+        assert!(limiter.check("first", format_args!("message")).is_some());
+        assert!(limiter.check("second", format_args!("message")).is_some());
+        assert!(limiter.check("first", format_args!("message")).is_none());
+    }
+
+    #[test]
+    fn zero_interval_emits_everything() {
+        let limiter = LogLimiter::new(Duration::ZERO);
+        assert!((0..100).all(|_| limiter.check("resync", format_args!("message")).is_some()));
+    }
+
+    #[test]
+    fn suppressed_messages_are_not_formatted() {
+        // Counts how often it is formatted
+        struct Counted(Cell<u32>);
+        impl fmt::Display for Counted {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.0.set(self.0.get() + 1);
+                f.write_str("message")
+            }
+        }
+
+        let limiter = LogLimiter::new(Duration::from_secs(60));
+        let counted = Counted(Cell::new(0));
+        let emitted = (0..1000)
+            .filter_map(|_| limiter.check("invalid frame", format_args!("{}", counted)))
+            .count();
+        assert_eq!(emitted, 1);
+        assert_eq!(counted.0.get(), 1);
+    }
+}
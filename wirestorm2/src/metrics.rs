@@ -0,0 +1,407 @@
+//! Runtime Metrics
+//!
+//! Counters shared by every connection thread through an `Arc<Metrics>`. Each counter
+//! is a relaxed atomic, so updating one on the frame path costs a single uncontended
+//! add; a [`Snapshot`] reads them all for logging or export.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use wirestorm_core::CtmpError;
+
+/// Proxy-wide counters, updated in the parse and broadcast paths.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    messages_forwarded: AtomicU64,
+    bytes_forwarded: AtomicU64,
+    checksum_drops: AtomicU64,
+    length_drops: AtomicU64,
+    denied_frames: AtomicU64,
+    anomaly_drops: AtomicU64,
+    max_payload: AtomicU64,
+    deadline_skips: AtomicU64,
+    audit_drops: AtomicU64,
+    resyncs: AtomicU64,
+    clean_disconnects: AtomicU64,
+    error_disconnects: AtomicU64,
+    sources_connected: AtomicU64,
+    destinations_connected: AtomicU64,
+    active_sources: AtomicU64,
+    active_destinations: AtomicU64,
+    parse_errors: ParseErrorCounters,
+}
+
+/// One counter per [`CtmpError`] variant.
+#[derive(Debug, Default)]
+struct ParseErrorCounters {
+    unexpected_eof: AtomicU64,
+    bad_magic: AtomicU64,
+    bad_reserved: AtomicU64,
+    payload_too_large: AtomicU64,
+    checksum_mismatch: AtomicU64,
+    io: AtomicU64,
+}
+
+/// Point-in-time copy of every counter in [`Metrics`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Messages handed to the destinations by the broadcaster
+    pub messages_forwarded: u64,
+    /// Bytes in those messages, counted once however many destinations there are
+    pub bytes_forwarded: u64,
+    /// Frames dropped because their checksum didn't match
+    pub checksum_drops: u64,
+    /// Frames dropped because their payload length wasn't the required one
+    pub length_drops: u64,
+    /// Frames dropped because their payload matched a deny pattern
+    pub denied_frames: u64,
+    /// Frames dropped for a payload far larger than any forwarded before
+    pub anomaly_drops: u64,
+    /// Longest payload forwarded so far, in bytes
+    pub max_payload: u64,
+    /// Frames skipped for one destination because they waited past the frame deadline
+    pub deadline_skips: u64,
+    /// Frames dropped because the audit sink couldn't be sent them
+    pub audit_drops: u64,
+    /// Times a source stream was resynchronized after a framing error
+    pub resyncs: u64,
+    /// Sources and destinations whose connection ended cleanly: closed by the client,
+    /// drained, expired or shut down
+    pub clean_disconnects: u64,
+    /// Sources and destinations dropped on an error, such as an invalid frame, a failed
+    /// write or a limit being exceeded
+    pub error_disconnects: u64,
+    /// Sources that have connected since startup
+    pub sources_connected: u64,
+    /// Destinations that have connected since startup
+    pub destinations_connected: u64,
+    /// Sources connected right now
+    pub active_sources: u64,
+    /// Destinations connected right now
+    pub active_destinations: u64,
+    /// Frames rejected by the parser, by error
+    pub parse_errors: ParseErrors,
+}
+
+/// Frames rejected by the parser, one count per [`CtmpError`] variant.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseErrors {
+    pub unexpected_eof: u64,
+    pub bad_magic: u64,
+    pub bad_reserved: u64,
+    pub payload_too_large: u64,
+    pub checksum_mismatch: u64,
+    pub io: u64,
+}
+
+impl ParseErrors {
+    /// Lists each count with the error's name, for export.
+    pub fn by_kind(&self) -> [(&'static str, u64); 6] {
+        [
+            ("unexpected_eof", self.unexpected_eof),
+            ("bad_magic", self.bad_magic),
+            ("bad_reserved", self.bad_reserved),
+            ("payload_too_large", self.payload_too_large),
+            ("checksum_mismatch", self.checksum_mismatch),
+            ("io", self.io),
+        ]
+    }
+}
+
+impl Metrics {
+    /// Records a message of `len` bytes fanned out by the broadcaster.
+    pub fn record_forwarded(&self, len: usize) {
+        self.messages_forwarded.fetch_add(1, Ordering::Relaxed);
+        self.bytes_forwarded.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Records a frame dropped for a bad checksum.
+    pub fn record_checksum_drop(&self) {
+        self.checksum_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a frame dropped for its payload length.
+    pub fn record_length_drop(&self) {
+        self.length_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a frame rejected by the parser with `error`.
+    pub fn record_parse_error(&self, error: &CtmpError) {
+        let counters = &self.parse_errors;
+        let counter = match error {
+            CtmpError::UnexpectedEof => &counters.unexpected_eof,
+            CtmpError::BadMagic(_) => &counters.bad_magic,
+            CtmpError::BadReserved => &counters.bad_reserved,
+            CtmpError::PayloadTooLarge { .. } => &counters.payload_too_large,
+            CtmpError::ChecksumMismatch { .. } => &counters.checksum_mismatch,
+            CtmpError::Io(_) => &counters.io,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the name `error` is counted under, as listed by [`ParseErrors::by_kind`].
+    pub fn parse_error_kind(error: &CtmpError) -> &'static str {
+        match error {
+            CtmpError::UnexpectedEof => "unexpected_eof",
+            CtmpError::BadMagic(_) => "bad_magic",
+            CtmpError::BadReserved => "bad_reserved",
+            CtmpError::PayloadTooLarge { .. } => "payload_too_large",
+            CtmpError::ChecksumMismatch { .. } => "checksum_mismatch",
+            CtmpError::Io(_) => "io",
+        }
+    }
+
+    /// Records a frame dropped for matching a deny pattern.
+    pub fn record_denied_frame(&self) {
+        self.denied_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a frame dropped for an anomalously large payload.
+    pub fn record_anomaly_drop(&self) {
+        self.anomaly_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a payload of `len` bytes passed to the broadcaster, raising the
+    /// longest payload seen if it is longer.
+    pub fn record_payload_len(&self, len: usize) {
+        self.max_payload.fetch_max(len as u64, Ordering::Relaxed);
+    }
+
+    /// Longest payload forwarded so far, in bytes.
+    pub fn max_payload(&self) -> u64 {
+        self.max_payload.load(Ordering::Relaxed)
+    }
+
+    /// Records a frame skipped for a destination that couldn't send it in time.
+    pub fn record_deadline_skip(&self) {
+        self.deadline_skips.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a frame dropped because the audit sink was unavailable.
+    pub fn record_audit_drop(&self) {
+        self.audit_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a source stream resynchronized after a framing error.
+    pub fn record_resync(&self) {
+        self.resyncs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a source starting to be read.
+    pub fn record_source_connected(&self) {
+        self.sources_connected.fetch_add(1, Ordering::Relaxed);
+        self.active_sources.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a source connection ending, cleanly or on an error.
+    pub fn record_source_disconnected(&self, clean: bool) {
+        self.active_sources.fetch_sub(1, Ordering::Relaxed);
+        self.record_disconnect(clean);
+    }
+
+    /// Records a destination joining the broadcast.
+    pub fn record_destination_connected(&self) {
+        self.destinations_connected.fetch_add(1, Ordering::Relaxed);
+        self.active_destinations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a destination connection ending, cleanly or on an error.
+    pub fn record_destination_disconnected(&self, clean: bool) {
+        self.active_destinations.fetch_sub(1, Ordering::Relaxed);
+        self.record_disconnect(clean);
+    }
+
+    fn record_disconnect(&self, clean: bool) {
+        let counter = if clean { &self.clean_disconnects } else { &self.error_disconnects };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Reads every counter. Counters are read one at a time, so a snapshot taken
+    /// under load may be a frame or two out of step between fields.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            messages_forwarded: self.messages_forwarded.load(Ordering::Relaxed),
+            bytes_forwarded: self.bytes_forwarded.load(Ordering::Relaxed),
+            checksum_drops: self.checksum_drops.load(Ordering::Relaxed),
+            length_drops: self.length_drops.load(Ordering::Relaxed),
+            denied_frames: self.denied_frames.load(Ordering::Relaxed),
+            anomaly_drops: self.anomaly_drops.load(Ordering::Relaxed),
+            max_payload: self.max_payload.load(Ordering::Relaxed),
+            deadline_skips: self.deadline_skips.load(Ordering::Relaxed),
+            audit_drops: self.audit_drops.load(Ordering::Relaxed),
+            resyncs: self.resyncs.load(Ordering::Relaxed),
+            clean_disconnects: self.clean_disconnects.load(Ordering::Relaxed),
+            error_disconnects: self.error_disconnects.load(Ordering::Relaxed),
+            sources_connected: self.sources_connected.load(Ordering::Relaxed),
+            destinations_connected: self.destinations_connected.load(Ordering::Relaxed),
+            active_sources: self.active_sources.load(Ordering::Relaxed),
+            active_destinations: self.active_destinations.load(Ordering::Relaxed),
+            parse_errors: self.parse_errors.snapshot(),
+        }
+    }
+}
+
+impl ParseErrorCounters {
+    fn snapshot(&self) -> ParseErrors {
+        ParseErrors {
+            unexpected_eof: self.unexpected_eof.load(Ordering::Relaxed),
+            bad_magic: self.bad_magic.load(Ordering::Relaxed),
+            bad_reserved: self.bad_reserved.load(Ordering::Relaxed),
+            payload_too_large: self.payload_too_large.load(Ordering::Relaxed),
+            checksum_mismatch: self.checksum_mismatch.load(Ordering::Relaxed),
+            io: self.io.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Totals for one source connection, updated by its handler and read by the control port.
+#[derive(Debug, Default)]
+pub struct SourceCounters {
+    frames: AtomicU64,
+    bytes: AtomicU64,
+    resyncs: AtomicU64,
+}
+
+/// Point-in-time copy of a [`SourceCounters`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SourceTotals {
+    /// Valid frames received and passed to the broadcaster
+    pub frames: u64,
+    /// Bytes in those frames, headers included
+    pub bytes: u64,
+    /// Times the stream was resynchronized after a framing error
+    pub resyncs: u64,
+}
+
+impl SourceCounters {
+    /// Records a frame of `len` bytes passed to the broadcaster.
+    pub fn record_frame(&self, len: usize) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Records the stream being resynchronized after a framing error.
+    pub fn record_resync(&self) {
+        self.resyncs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Reads every counter.
+    pub fn totals(&self) -> SourceTotals {
+        SourceTotals {
+            frames: self.frames.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            resyncs: self.resyncs.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Display for SourceTotals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frames={} bytes={} resyncs={}", self.frames, self.bytes, self.resyncs)
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} messages ({} bytes) forwarded, {} checksum drops, {} length drops, \
+             {} denied frames, {} deadline skips, {} audit drops, {} resyncs, \
+             {} clean and {} error disconnects, {} sources and {} destinations active",
+            self.messages_forwarded,
+            self.bytes_forwarded,
+            self.checksum_drops,
+            self.length_drops,
+            self.denied_frames,
+            self.deadline_skips,
+            self.audit_drops,
+            self.resyncs,
+            self.clean_disconnects,
+            self.error_disconnects,
+            self.active_sources,
+            self.active_destinations
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn counters_are_shared_across_threads() {
+        let metrics = Arc::new(Metrics::default());
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let metrics = Arc::clone(&metrics);
+                thread::spawn(move || {
+                    metrics.record_source_connected();
+                    metrics.record_destination_connected();
+                    for _ in 0..1000 {
+                        metrics.record_forwarded(10);
+                    }
+                    metrics.record_checksum_drop();
+                    metrics.record_length_drop();
+                    metrics.record_deadline_skip();
+                    metrics.record_audit_drop();
+                    metrics.record_source_disconnected(false);
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(
+            snapshot,
+            Snapshot {
+                messages_forwarded: 4000,
+                bytes_forwarded: 40_000,
+                checksum_drops: 4,
+                length_drops: 4,
+                denied_frames: 0,
+                anomaly_drops: 0,
+                max_payload: 0,
+                deadline_skips: 4,
+                audit_drops: 4,
+                resyncs: 0,
+                clean_disconnects: 0,
+                error_disconnects: 4,
+                sources_connected: 4,
+                destinations_connected: 4,
+                active_sources: 0,
+                active_destinations: 4,
+                parse_errors: ParseErrors::default(),
+            }
+        );
+        assert_eq!(
+            snapshot.to_string(),
+            "4000 messages (40000 bytes) forwarded, 4 checksum drops, 4 length drops, \
+             0 denied frames, 4 deadline skips, 4 audit drops, 0 resyncs, \
+             0 clean and 4 error disconnects, 0 sources and 4 destinations active"
+        );
+    }
+
+    #[test]
+    fn each_parse_error_has_its_own_counter() {
+        let metrics = Metrics::default();
+        let errors = [
+            CtmpError::UnexpectedEof,
+            CtmpError::BadMagic(0x00),
+            CtmpError::BadReserved,
+            CtmpError::PayloadTooLarge { length: 10, max: 5 },
+            CtmpError::ChecksumMismatch { expected: 1, actual: 2 },
+            CtmpError::Io(std::io::ErrorKind::TimedOut.into()),
+        ];
+        for error in &errors {
+            metrics.record_parse_error(error);
+        }
+
+        let counts = metrics.snapshot().parse_errors.by_kind();
+        assert_eq!(counts.len(), errors.len());
+        assert!(counts.iter().all(|&(_, count)| count == 1), "{:?}", counts);
+    }
+}
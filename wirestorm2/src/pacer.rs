@@ -0,0 +1,87 @@
+//! Throughput Pacing
+//!
+//! Caps the total rate at which the broadcaster forwards bytes, for deployments behind
+//! a rate-limited downstream link. Frames are spaced out as a leaky bucket: each frame
+//! is released once the previous one has "drained" at the configured rate, so a burst
+//! from the sources leaves the proxy as a steady stream instead of a spike. Idle time
+//! is not banked, so a quiet period can't be followed by a burst above the limit.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Spaces frames out to keep the forwarded byte rate near a limit.
+pub struct Pacer {
+    bytes_per_sec: u64,
+    next_release: Instant, // Earliest time the next frame may go out
+}
+
+impl Pacer {
+    /// Creates a pacer releasing at most `bytes_per_sec` bytes per second.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Pacer {
+            bytes_per_sec: bytes_per_sec.max(1),
+            next_release: Instant::now(),
+        }
+    }
+
+    /// Blocks until a frame of `len` bytes may be released, then books its share of the rate.
+    pub fn pace(&mut self, len: usize) {
+        let now = Instant::now();
+        if self.next_release > now {
+            thread::sleep(self.next_release - now);
+        }
+
+        let released = self.next_release.max(now);
+        self.next_release =
+            released + Duration::from_secs_f64(len as f64 / self.bytes_per_sec as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_is_smoothed_to_the_limit() {
+        const RATE: u64 = 1_000_000;
+        const FRAME: usize = 25_000;
+        const WINDOW: Duration = Duration::from_millis(100);
+
+        let mut pacer = Pacer::new(RATE);
+        let start = Instant::now();
+        let mut releases = Vec::new();
+        for _ in 0..24 {
+            pacer.pace(FRAME);
+            releases.push(start.elapsed());
+        }
+
+        // 24 frames need 23 frame-times between the first and last release
+        let expected = Duration::from_secs_f64(23.0 * FRAME as f64 / RATE as f64);
+        assert!(start.elapsed() >= expected);
+
+        // No 100ms window carries more than its share plus one frame
+        let limit = (RATE as f64 * WINDOW.as_secs_f64()) as usize + FRAME;
+        for (i, &window_start) in releases.iter().enumerate() {
+            let bytes = releases[i..]
+                .iter()
+                .take_while(|&&at| at < window_start + WINDOW)
+                .count()
+                * FRAME;
+            assert!(bytes <= limit, "{} bytes in window at {:?}", bytes, window_start);
+        }
+    }
+
+    #[test]
+    fn idle_time_is_not_banked() {
+        let mut pacer = Pacer::new(1_000_000);
+        pacer.pace(10);
+        thread::sleep(Duration::from_millis(50));
+
+        // After a quiet period the next large frame goes at once, but the one after
+        // still waits for it to drain
+        let start = Instant::now();
+        pacer.pace(50_000);
+        pacer.pace(10);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
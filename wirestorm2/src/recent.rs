@@ -0,0 +1,79 @@
+//! Recent Disconnect Errors
+//!
+//! A short history of the connections most recently cut off on an error, so an
+//! intermittent disconnect can still be diagnosed after its log lines have scrolled
+//! away. Each entry keeps the client, its role, the kind of error and the error
+//! itself, and only the last [`CAPACITY`] are kept. The control port's `recent`
+//! command lists them. Like [`crate::events`], the history is process-wide.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::events::Role;
+
+/// Disconnects kept in the history; older ones are forgotten
+pub const CAPACITY: usize = 32;
+
+/// One connection cut off on an error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentError {
+    /// Milliseconds since the Unix epoch
+    pub timestamp_ms: u128,
+    pub role: Role,
+    pub id: u64,
+    pub addr: Option<SocketAddr>,
+    /// Why the connection was cut off, e.g. `write_failed` or `bad_magic`
+    pub kind: &'static str,
+    /// The underlying error
+    pub error: String,
+}
+
+impl RecentError {
+    /// Formats the entry as one line, without a trailing newline.
+    pub fn to_line(&self) -> String {
+        let addr = match self.addr {
+            Some(addr) => addr.to_string(),
+            None => "(unknown addr)".to_string(),
+        };
+        format!(
+            "#{} {} {} at_ms={} kind={} error={}",
+            self.id,
+            self.role.as_str(),
+            addr,
+            self.timestamp_ms,
+            self.kind,
+            self.error
+        )
+    }
+}
+
+/// The history, oldest first
+static RECENT: Mutex<VecDeque<RecentError>> = Mutex::new(VecDeque::new());
+
+/// Records that client `id` was cut off for `kind` because of `error`, forgetting the
+/// oldest entry if the history is full.
+pub fn record(role: Role, id: u64, addr: Option<SocketAddr>, kind: &'static str, error: &str) {
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis());
+    let mut recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    if recent.len() == CAPACITY {
+        recent.pop_front();
+    }
+    recent.push_back(RecentError {
+        timestamp_ms,
+        role,
+        id,
+        addr,
+        kind,
+        error: error.to_string(),
+    });
+}
+
+/// Returns the history, most recent first.
+pub fn entries() -> Vec<RecentError> {
+    let recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    recent.iter().rev().cloned().collect()
+}
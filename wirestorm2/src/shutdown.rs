@@ -0,0 +1,283 @@
+//! Graceful Shutdown
+//!
+//! SIGINT and SIGTERM set a shared flag instead of killing the proxy outright. The
+//! accept loops and connection threads poll the flag, so listeners stop accepting,
+//! sources finish the frame they are reading, and destinations are sent everything
+//! already queued before their sockets are shut down.
+
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use wirestorm_core::HEADER_LEN;
+
+/// How often blocked loops wake up to check the shutdown flag
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Flag shared by every thread; set once shutdown has been requested.
+pub type ShutdownFlag = Arc<AtomicBool>;
+
+/// Flag set by the signal handler, registered by [`install`]
+static SIGNAL_FLAG: OnceLock<ShutdownFlag> = OnceLock::new();
+
+/// Returns true once shutdown has been requested.
+pub fn requested(flag: &AtomicBool) -> bool {
+    flag.load(Ordering::SeqCst)
+}
+
+/// Requests shutdown, as the signal handler does.
+pub fn request(flag: &AtomicBool) {
+    flag.store(true, Ordering::SeqCst);
+}
+
+/// Sets `flag` when the process receives SIGINT or SIGTERM.
+/// Only the first flag installed is registered.
+pub fn install(flag: &ShutdownFlag) {
+    if SIGNAL_FLAG.set(Arc::clone(flag)).is_err() {
+        return;
+    }
+
+    #[cfg(unix)]
+    // SAFETY: the handler only performs an atomic store, which is async-signal-safe
+    unsafe {
+        sys::signal(sys::SIGINT, on_signal);
+        sys::signal(sys::SIGTERM, on_signal);
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(_signum: std::os::raw::c_int) {
+    if let Some(flag) = SIGNAL_FLAG.get() {
+        request(flag);
+    }
+}
+
+/// The C library is already linked by std, so the one function needed is declared here.
+#[cfg(unix)]
+mod sys {
+    use std::os::raw::c_int;
+
+    pub const SIGINT: c_int = 2;
+    pub const SIGTERM: c_int = 15;
+
+    unsafe extern "C" {
+        pub fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+}
+
+/// Reads a source stream, reporting a clean EOF once shutdown is requested between frames.
+///
+/// The stream is polled every [`POLL_INTERVAL`]. A frame that has started is still read
+/// to completion after shutdown, as long as its bytes keep arriving; a source that stalls
+/// mid-frame once shutdown is requested gets a `TimedOut` error instead. Independently
+/// of shutdown, a source that sends nothing for `read_timeout` also gets `TimedOut`.
+/// Passing a deadline set with [`FrameReader::set_deadline`] ends the stream the same
+/// way shutdown does. With a header timeout set by [`FrameReader::set_header_timeout`],
+/// a frame whose header isn't complete that long after its first byte arrived also
+/// gets `TimedOut`, so a source can't hold its connection by dripping bytes.
+pub struct FrameReader {
+    stream: TcpStream,
+    read_timeout: Option<Duration>,
+    shutdown: ShutdownFlag,
+    deadline: Option<Instant>,        // When to end the stream regardless of shutdown
+    header_timeout: Option<Duration>, // How long a header may take once started
+    frame_started: Option<Instant>,   // When the current frame's first byte arrived
+    frame_bytes: usize,               // Bytes of the current frame read so far
+    last_data: Instant,               // When the source last sent anything
+}
+
+impl FrameReader {
+    /// Wraps `stream`, setting its socket timeout to poll every [`POLL_INTERVAL`]
+    /// (or sooner, if `read_timeout` is shorter).
+    pub fn new(
+        stream: TcpStream,
+        read_timeout: Option<Duration>,
+        shutdown: ShutdownFlag,
+    ) -> io::Result<Self> {
+        let poll = read_timeout.map_or(POLL_INTERVAL, |timeout| timeout.min(POLL_INTERVAL));
+        stream.set_read_timeout(Some(poll))?;
+        Ok(FrameReader {
+            stream,
+            read_timeout,
+            shutdown,
+            deadline: None,
+            header_timeout: None,
+            frame_started: None,
+            frame_bytes: 0,
+            last_data: Instant::now(),
+        })
+    }
+
+    /// Marks the start of the next frame; call before each parse.
+    pub fn start_frame(&mut self) {
+        self.frame_started = None;
+        self.frame_bytes = 0;
+    }
+
+    /// Ends the stream at the first frame boundary after `deadline`, finishing any
+    /// frame already started. `None` removes the deadline.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Limits how long a frame's header may take to arrive, counted from its first
+    /// byte. `None` removes the limit.
+    pub fn set_header_timeout(&mut self, timeout: Option<Duration>) {
+        self.header_timeout = timeout;
+    }
+
+    /// Returns true once the deadline, if any, has passed.
+    pub fn expired(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Whether any byte of the current frame has been read.
+    fn in_frame(&self) -> bool {
+        self.frame_started.is_some()
+    }
+
+    /// Fails once the current frame's header has taken longer than the header timeout.
+    fn check_header_time(&self) -> io::Result<()> {
+        if let (Some(timeout), Some(started)) = (self.header_timeout, self.frame_started)
+            && self.frame_bytes < HEADER_LEN
+            && started.elapsed() >= timeout
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("header not complete within {:?}", timeout),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Read for FrameReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // A busy source never times out, so check the deadline before each frame
+        if !self.in_frame() && self.expired() {
+            return Ok(0);
+        }
+
+        loop {
+            // Checked between reads too, as a dripping source never goes quiet
+            self.check_header_time()?;
+            match self.stream.read(buf) {
+                Ok(n) => {
+                    if n > 0 {
+                        self.last_data = Instant::now();
+                        self.frame_started.get_or_insert(self.last_data);
+                        self.frame_bytes += n;
+                    }
+                    return Ok(n);
+                }
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    if let Some(timeout) = self.read_timeout
+                        && self.last_data.elapsed() >= timeout
+                    {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("no data from source for {:?}", timeout),
+                        ));
+                    }
+                    let shutting_down = requested(&self.shutdown);
+                    if !shutting_down && !self.expired() {
+                        continue; // Idle source; keep waiting
+                    }
+                    if !self.in_frame() {
+                        return Ok(0); // Between frames; end the stream cleanly
+                    }
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        if shutting_down {
+                            "source stalled mid-frame during shutdown"
+                        } else {
+                            "source stalled mid-frame at the end of its lifetime"
+                        },
+                    ));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn idle_reader_ends_cleanly_on_shutdown() {
+        let (_client, server) = pair();
+        let flag: ShutdownFlag = Arc::new(AtomicBool::new(false));
+        let mut reader = FrameReader::new(server, None, Arc::clone(&flag)).unwrap();
+
+        request(&flag);
+        assert_eq!(reader.read(&mut [0u8; 8]).unwrap(), 0);
+    }
+
+    #[test]
+    fn stalled_frame_times_out_on_shutdown() {
+        let (mut client, server) = pair();
+        let flag: ShutdownFlag = Arc::new(AtomicBool::new(false));
+        let mut reader = FrameReader::new(server, None, Arc::clone(&flag)).unwrap();
+
+        client.write_all(&[0xCC, 0x00]).unwrap();
+        assert_eq!(reader.read(&mut [0u8; 8]).unwrap(), 2);
+
+        request(&flag);
+        let err = reader.read(&mut [0u8; 8]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // A new frame that has not started yet ends cleanly instead
+        reader.start_frame();
+        assert_eq!(reader.read(&mut [0u8; 8]).unwrap(), 0);
+    }
+
+    #[test]
+    fn deadline_ends_the_stream_after_the_current_frame() {
+        let (mut client, server) = pair();
+        let flag: ShutdownFlag = Arc::new(AtomicBool::new(false));
+        let mut reader = FrameReader::new(server, None, flag).unwrap();
+
+        // A frame started before the deadline is still read to the end
+        client.write_all(&[0xCC, 0x00]).unwrap();
+        assert_eq!(reader.read(&mut [0u8; 2]).unwrap(), 2);
+        reader.set_deadline(Some(Instant::now()));
+        assert!(reader.expired());
+        client.write_all(&[0x00, 0x00]).unwrap();
+        assert_eq!(reader.read(&mut [0u8; 2]).unwrap(), 2);
+
+        // The next one isn't started, even though its bytes are waiting
+        client.write_all(&[0xCC, 0x00]).unwrap();
+        reader.start_frame();
+        assert_eq!(reader.read(&mut [0u8; 2]).unwrap(), 0);
+    }
+
+    #[test]
+    fn silent_source_times_out() {
+        let (mut client, server) = pair();
+        let flag: ShutdownFlag = Arc::new(AtomicBool::new(false));
+        let timeout = Duration::from_millis(200);
+        let mut reader = FrameReader::new(server, Some(timeout), flag).unwrap();
+
+        // Sending data restarts the clock
+        client.write_all(&[0xCC]).unwrap();
+        assert_eq!(reader.read(&mut [0u8; 8]).unwrap(), 1);
+
+        let started = Instant::now();
+        let err = reader.read(&mut [0u8; 8]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= timeout);
+    }
+}
@@ -0,0 +1,102 @@
+//! StatsD Push
+//!
+//! For deployments whose monitoring collects metrics by push rather than by scraping
+//! the admin endpoint. Every interval the reporter sends the metrics the admin
+//! endpoint exports to a StatsD server over UDP, one `name:value|type` line each:
+//! counters as the increase since the previous push, gauges as their current value.
+//! Names drop the Prometheus `_total` suffix and gain a `wirestorm2.` prefix, and the
+//! parse error family is sent as one counter per kind. UDP never blocks the proxy, so
+//! a server that is down just loses the pushes made meanwhile.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::admin;
+use crate::log_limit;
+use crate::metrics::{Metrics, Snapshot};
+use crate::shutdown::{self, ShutdownFlag};
+
+/// Prefix put in front of every metric name
+const PREFIX: &str = "wirestorm2.";
+
+/// Largest packet sent, keeping each one within a typical Ethernet MTU
+const MAX_PACKET: usize = 1400;
+
+/// Pushes `metrics` to the StatsD server at `addr` every `interval` until shutdown is
+/// requested, with a final push so the last interval isn't lost.
+pub fn run(
+    addr: SocketAddr,
+    metrics: &Metrics,
+    interval: Duration,
+    shutdown: &ShutdownFlag,
+) -> io::Result<()> {
+    let bind_addr: SocketAddr = match addr {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.connect(addr)?;
+
+    let mut last = HashMap::new();
+    let mut last_push = Instant::now();
+    while !shutdown::requested(shutdown) {
+        thread::sleep(shutdown::POLL_INTERVAL.min(interval));
+        if last_push.elapsed() >= interval {
+            push(&socket, &metrics.snapshot(), &mut last);
+            last_push = Instant::now();
+        }
+    }
+    push(&socket, &metrics.snapshot(), &mut last);
+    Ok(())
+}
+
+/// Sends `snapshot` in as few packets as fit, updating `last` with the counter values
+/// the next push takes its increases from.
+fn push(socket: &UdpSocket, snapshot: &Snapshot, last: &mut HashMap<String, u64>) {
+    for packet in packets(&lines(snapshot, last)) {
+        if let Err(e) = socket.send(packet.as_bytes()) {
+            log_limit::warn("statsd", format_args!("Failed to push metrics to StatsD: {}", e));
+        }
+    }
+}
+
+/// Renders `snapshot` as StatsD lines, with counters as their increase over `last`.
+fn lines(snapshot: &Snapshot, last: &mut HashMap<String, u64>) -> Vec<String> {
+    let parse_errors = snapshot.parse_errors.by_kind().map(|(kind, value)| {
+        (format!("parse_errors.{}", kind), "counter", value)
+    });
+    let metrics = admin::metric_list(snapshot).into_iter().map(|(name, kind, _, value)| {
+        (name.trim_end_matches("_total").to_string(), kind, value)
+    });
+
+    let mut lines = Vec::new();
+    for (name, kind, value) in metrics.chain(parse_errors) {
+        if kind == "gauge" {
+            lines.push(format!("{}{}:{}|g", PREFIX, name, value));
+            continue;
+        }
+        let previous = last.insert(name.clone(), value).unwrap_or(0);
+        lines.push(format!("{}{}:{}|c", PREFIX, name, value.saturating_sub(previous)));
+    }
+    lines
+}
+
+/// Joins `lines` with newlines into packets of at most [`MAX_PACKET`] bytes.
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets = vec![String::new()];
+    for line in lines {
+        let packet = packets.last_mut().unwrap();
+        if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET {
+            packets.push(line.clone());
+            continue;
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    packets
+}